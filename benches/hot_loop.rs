@@ -0,0 +1,46 @@
+//! Compares `run` and `run_fast` on a pair of nested countdown loops.
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use vm_challenge::asm::assemble;
+use vm_challenge::machine::VM;
+
+const PROGRAM: &str = "
+    set r2 100
+outer:
+    set r1 32767
+inner:
+    add r1 r1 32767 ; minus one
+    jt r1 inner
+    add r2 r2 32767
+    jt r2 outer
+    halt
+";
+
+const SAMPLES: u32 = 10;
+
+fn time(program: &[u16], run: fn(&mut VM)) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..SAMPLES {
+        let mut vm = VM::new(program.to_vec()).unwrap();
+        let start = Instant::now();
+        run(black_box(&mut vm));
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn main() {
+    let program = assemble(PROGRAM).expect("Benchmark program should assemble");
+    let slow = time(&program, |vm| {
+        vm.run().unwrap();
+    });
+    let fast = time(&program, |vm| {
+        vm.run_fast().unwrap();
+    });
+    println!("run:      {slow:?}");
+    println!("run_fast: {fast:?}");
+    println!("speedup:  {:.1}x", slow.as_secs_f64() / fast.as_secs_f64());
+}
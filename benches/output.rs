@@ -0,0 +1,71 @@
+//! Compares writing a lot of output straight to a file with going through a
+//! `BufWriter`, as the CLI does for stdout, along with the sink `VM::new`
+//! starts with for reference. The file is a real one in the temporary
+//! directory, so every unbuffered write is a system call, as it would be on
+//! a terminal. Run with `cargo bench`.
+
+use std::env;
+use std::fs::{self, File};
+use std::hint::black_box;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+use vm_challenge::asm::assemble;
+use vm_challenge::machine::VM;
+
+/// Prints 100,000 characters, a line of 50 at a time
+const PROGRAM: &str = "
+    set r2 2000
+line:
+    set r1 50
+char:
+    out 'x'
+    add r1 r1 32767 ; minus one
+    jt r1 char
+    out '\\n'
+    add r2 r2 32767
+    jt r2 line
+    halt
+";
+
+const SAMPLES: u32 = 10;
+
+/// The fastest run. `None` leaves the output as `VM::new` set it up.
+fn time(program: &[u16], sink: Option<&dyn Fn() -> Box<dyn Write>>) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..SAMPLES {
+        let mut vm = VM::new(program.to_vec()).unwrap();
+        if let Some(sink) = sink {
+            vm.set_output(sink());
+        }
+        let start = Instant::now();
+        black_box(&mut vm).run().unwrap();
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn create(path: &Path) -> File {
+    File::create(path).unwrap_or_else(|err| {
+        eprintln!("Could not create {}: {err}", path.display());
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let program = assemble(PROGRAM).expect("Benchmark program should assemble");
+    let path = env::temp_dir().join(format!("vm_challenge-output-{}", process::id()));
+    let default = time(&program, None);
+    let unbuffered = time(&program, Some(&|| Box::new(create(&path))));
+    let buffered = time(&program, Some(&|| Box::new(BufWriter::new(create(&path)))));
+    let _ = fs::remove_file(&path);
+    println!("default:    {default:?}");
+    println!("unbuffered: {unbuffered:?}");
+    println!("buffered:   {buffered:?}");
+    println!(
+        "speedup:    {:.1}x",
+        unbuffered.as_secs_f64() / buffered.as_secs_f64()
+    );
+}
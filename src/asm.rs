@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::machine::Opcode;
+use crate::value::Value;
+
+/// Something wrong with the source, along with the (1-based) line it's on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownMnemonic {
+        line: usize,
+        mnemonic: String,
+    },
+    WrongArgCount {
+        line: usize,
+        opcode: Opcode,
+        found: usize,
+    },
+    BadOperand {
+        line: usize,
+        operand: String,
+    },
+    DuplicateLabel {
+        line: usize,
+        label: String,
+    },
+    UnresolvedLabel {
+        line: usize,
+        label: String,
+    },
+}
+
+impl AsmError {
+    pub fn line(&self) -> usize {
+        match *self {
+            Self::UnknownMnemonic { line, .. }
+            | Self::WrongArgCount { line, .. }
+            | Self::BadOperand { line, .. }
+            | Self::DuplicateLabel { line, .. }
+            | Self::UnresolvedLabel { line, .. } => line,
+        }
+    }
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}: ", self.line())?;
+        match self {
+            Self::UnknownMnemonic { mnemonic, .. } => write!(f, "Unknown mnemonic {mnemonic}"),
+            Self::WrongArgCount { opcode, found, .. } => write!(
+                f,
+                "{opcode} takes {} arguments but was given {found}",
+                opcode.num_args()
+            ),
+            Self::BadOperand { operand, .. } => write!(f, "Can't understand operand {operand}"),
+            Self::DuplicateLabel { label, .. } => write!(f, "Label {label} is already defined"),
+            Self::UnresolvedLabel { label, .. } => write!(f, "Label {label} is never defined"),
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+enum Operand {
+    Word(u16),
+    Label(String),
+}
+
+/// Splits a line into whitespace separated tokens, keeping quoted character
+/// literals intact and dropping anything after a `;`
+fn tokenize(text: &str, line: usize) -> Result<Vec<String>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '\'' {
+            let mut token = String::from(chars.next().unwrap());
+            loop {
+                let Some(c) = chars.next() else {
+                    return Err(AsmError::BadOperand {
+                        line,
+                        operand: token,
+                    });
+                };
+                token.push(c);
+                match c {
+                    '\\' => token.extend(chars.next()),
+                    '\'' => break,
+                    _ => (),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_char(token: &str) -> Option<u16> {
+    let inner = token.strip_prefix('\'')?.strip_suffix('\'')?;
+    let c = match inner {
+        "\\n" => '\n',
+        "\\t" => '\t',
+        "\\r" => '\r',
+        "\\0" => '\0',
+        "\\\\" => '\\',
+        "\\'" => '\'',
+        _ => {
+            let mut chars = inner.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            c
+        }
+    };
+    u16::try_from(u32::from(c))
+        .ok()
+        .filter(|&n| n < Value::REGISTER_BASE)
+}
+
+fn is_label(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_operand(token: &str, line: usize) -> Result<Operand, AsmError> {
+    let bad_operand = || AsmError::BadOperand {
+        line,
+        operand: token.to_owned(),
+    };
+    if token.starts_with('\'') {
+        return parse_char(token).map(Operand::Word).ok_or_else(bad_operand);
+    }
+    if let Some(register) = token.strip_prefix('r').and_then(|n| n.parse::<u16>().ok()) {
+        if usize::from(register) >= Value::REGISTER_COUNT {
+            return Err(bad_operand());
+        }
+        return Ok(Operand::Word(Value::REGISTER_BASE + register));
+    }
+    if token.starts_with(|c: char| c.is_ascii_digit()) {
+        return token
+            .parse::<u16>()
+            .ok()
+            .filter(|&n| n < Value::REGISTER_BASE)
+            .map(Operand::Word)
+            .ok_or_else(bad_operand);
+    }
+    if is_label(token) {
+        return Ok(Operand::Label(token.to_owned()));
+    }
+    Err(bad_operand())
+}
+
+/// Compiles assembly source into a program. Each line holds an instruction
+/// such as `set r0 'H'` or `jmp loop`, optionally preceded by `label:`
+/// definitions. `db` emits its operands as raw words, and numeric labels
+/// like the address prefixes from the disassembler are ignored, so its
+/// listings can be fed straight back in.
+pub fn assemble(source: &str) -> Result<Vec<u16>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut words = Vec::new();
+    let mut fixups = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let tokens = tokenize(text, line)?;
+        let mut rest = &tokens[..];
+        while let Some((label, tail)) = rest.split_first() {
+            let Some(label) = label.strip_suffix(':') else {
+                break;
+            };
+            rest = tail;
+            if label.chars().all(|c| c.is_ascii_digit()) {
+                continue;
+            }
+            if !is_label(label) {
+                return Err(AsmError::BadOperand {
+                    line,
+                    operand: label.to_owned(),
+                });
+            }
+            if labels.insert(label.to_owned(), words.len()).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    label: label.to_owned(),
+                });
+            }
+        }
+        let Some((mnemonic, args)) = rest.split_first() else {
+            continue;
+        };
+        let is_data = mnemonic == "db";
+        if !is_data {
+            let opcode: Opcode = mnemonic.parse().map_err(|_| AsmError::UnknownMnemonic {
+                line,
+                mnemonic: mnemonic.to_owned(),
+            })?;
+            if args.len() != opcode.num_args() {
+                return Err(AsmError::WrongArgCount {
+                    line,
+                    opcode,
+                    found: args.len(),
+                });
+            }
+            words.push(opcode.to_u16());
+        }
+        for arg in args {
+            // Data can be any word at all, not just valid operands
+            if let Some(word) = arg.parse().ok().filter(|_| is_data) {
+                words.push(word);
+                continue;
+            }
+            match parse_operand(arg, line)? {
+                Operand::Word(word) => words.push(word),
+                Operand::Label(label) => {
+                    fixups.push((words.len(), label, line));
+                    words.push(0);
+                }
+            }
+        }
+    }
+    for (index, label, line) in fixups {
+        let &addr = labels
+            .get(&label)
+            .ok_or(AsmError::UnresolvedLabel { line, label })?;
+        words[index] = addr as u16;
+    }
+    Ok(words)
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::machine::Opcode;
+use crate::value::{Value, ValueState};
+
+fn format_operand(value: Value) -> String {
+    match value.get_value_state() {
+        ValueState::Number(n) => n.to_string(),
+        ValueState::Register(r) => format!("r{r}"),
+        ValueState::Invalid => value.to_string(),
+    }
+}
+
+fn format_char(value: Value) -> Option<String> {
+    let c = char::from_u32(value.raw().into()).filter(|c| c.is_ascii())?;
+    if c.is_ascii_graphic() || c == ' ' || c == '\n' {
+        Some(format!("{c:?}"))
+    } else {
+        None
+    }
+}
+
+/// Which operand, if any, holds the address an instruction might jump to
+fn jump_operand(opcode: Opcode) -> Option<usize> {
+    match opcode {
+        Opcode::Jmp | Opcode::Call => Some(0),
+        Opcode::Jt | Opcode::Jf => Some(1),
+        _ => None,
+    }
+}
+
+/// Renders the instruction at `addr` along with the address of the next one.
+/// Anything that doesn't decode as an instruction is a single word of `db`.
+fn disassemble_at(
+    memory: &[Value],
+    addr: usize,
+    labels: &HashMap<usize, String>,
+) -> (String, usize) {
+    let instruction = Opcode::try_from(memory[addr])
+        .ok()
+        .filter(|opcode| addr + opcode.num_args() < memory.len());
+    let Some(opcode) = instruction else {
+        return (format!("{addr:04}: db {}", memory[addr]), addr + 1);
+    };
+    let args = &memory[addr + 1..=addr + opcode.num_args()];
+    (
+        render_instruction(addr, &opcode, args, labels),
+        addr + opcode.num_args() + 1,
+    )
+}
+
+/// Renders a single instruction as `AAAA: mnemonic operand operand...`, where
+/// the address is zero padded to at least four digits, registers are `rN` and
+/// characters being output are quoted
+pub fn format_instruction(addr: usize, opcode: &Opcode, args: &[Value]) -> String {
+    render_instruction(addr, opcode, args, &HashMap::new())
+}
+
+fn render_instruction(
+    addr: usize,
+    opcode: &Opcode,
+    args: &[Value],
+    labels: &HashMap<usize, String>,
+) -> String {
+    let mut line = format!("{addr:04}: {opcode}");
+    let jump = jump_operand(*opcode);
+    for (i, &arg) in args.iter().enumerate() {
+        line.push(' ');
+        let label = match arg.get_value_state() {
+            ValueState::Number(n) if jump == Some(i) => labels.get(&(n as usize)),
+            _ => None,
+        };
+        match (opcode, label) {
+            (Opcode::Out, _) => {
+                line.push_str(&format_char(arg).unwrap_or_else(|| format_operand(arg)))
+            }
+            (_, Some(label)) => line.push_str(&format!("{label} ({arg})")),
+            _ => line.push_str(&format_operand(arg)),
+        }
+    }
+    line
+}
+
+/// Walks the program linearly, rendering one line per instruction. Anything
+/// that doesn't decode as an instruction is emitted one word at a time as `db`.
+pub fn disassemble(memory: &[Value]) -> Vec<String> {
+    disassemble_from(memory, 0, usize::MAX)
+}
+
+/// Like `disassemble`, but starts at `start` and stops after `count` lines or
+/// at the end of memory, whichever comes first
+pub fn disassemble_from(memory: &[Value], start: usize, count: usize) -> Vec<String> {
+    let labels = HashMap::new();
+    let mut lines = Vec::new();
+    let mut addr = start;
+    while addr < memory.len() && lines.len() < count {
+        let (line, next) = disassemble_at(memory, addr, &labels);
+        lines.push(line);
+        addr = next;
+    }
+    lines
+}
+
+/// Like `disassemble`, but only decodes the instructions that
+/// `reachable_instructions` can find. Everything else is assumed to be data,
+/// one word of `db` at a time, even where it happens to look like code.
+pub fn disassemble_code(memory: &[Value]) -> Vec<String> {
+    let reachable = reachable_instructions(memory);
+    let labels = HashMap::new();
+    let mut lines = Vec::new();
+    let mut addr = 0;
+    while addr < memory.len() {
+        if reachable.contains(&addr) {
+            let (line, next) = disassemble_at(memory, addr, &labels);
+            lines.push(line);
+            addr = next;
+        } else {
+            lines.push(format!("{addr:04}: db {}", memory[addr]));
+            addr += 1;
+        }
+    }
+    lines
+}
+
+/// Like `disassemble`, but with names for some addresses. Each name gets a
+/// `name:` line of its own before the instruction it belongs to, and jumps
+/// and calls there are rendered like `call print_string (1531)`.
+pub fn disassemble_with_labels(memory: &[Value], labels: &HashMap<usize, String>) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut addr = 0;
+    while addr < memory.len() {
+        if let Some(label) = labels.get(&addr) {
+            lines.push(format!("{label}:"));
+        }
+        let (line, next) = disassemble_at(memory, addr, labels);
+        lines.push(line);
+        addr = next;
+    }
+    lines
+}
+
+/// Words per hexdump row
+const HEXDUMP_WIDTH: usize = 8;
+
+/// Renders `len` words of memory from `start` as hex, eight to a row, with
+/// the address in hex down the side and printable ascii characters on the
+/// right. Anything hanging off the end of memory is left out.
+pub fn hexdump(memory: &[Value], start: usize, len: usize) -> String {
+    let end = start.saturating_add(len).min(memory.len());
+    let mut out = String::new();
+    for row_start in (start..end).step_by(HEXDUMP_WIDTH) {
+        let row = &memory[row_start..(row_start + HEXDUMP_WIDTH).min(end)];
+        let words: Vec<String> = row.iter().map(|v| format!("{:04x}", v.raw())).collect();
+        let text: String = row
+            .iter()
+            .map(|v| {
+                char::from_u32(v.raw().into())
+                    .filter(|c| c.is_ascii_graphic() || *c == ' ')
+                    .unwrap_or('.')
+            })
+            .collect();
+        out.push_str(&format!(
+            "{row_start:04x}: {:width$}  |{text}|\n",
+            words.join(" "),
+            width = HEXDUMP_WIDTH * 5 - 1
+        ));
+    }
+    out
+}
+
+/// Follows every path through the program from address 0, returning the
+/// addresses of the instructions found along with those of any jumps or calls
+/// through a register, which can't be followed without running the program
+fn trace_control_flow(memory: &[Value]) -> (BTreeSet<usize>, BTreeSet<usize>) {
+    let mut reachable = BTreeSet::new();
+    let mut indirect = BTreeSet::new();
+    let mut pending = vec![0];
+    while let Some(addr) = pending.pop() {
+        if addr >= memory.len() || reachable.contains(&addr) {
+            continue;
+        }
+        let instruction = Opcode::try_from(memory[addr])
+            .ok()
+            .filter(|opcode| addr + opcode.num_args() < memory.len());
+        // Running into data means this path is probably never taken
+        let Some(opcode) = instruction else {
+            continue;
+        };
+        reachable.insert(addr);
+        let next = addr + opcode.num_args() + 1;
+        let target = jump_operand(opcode).map(|i| memory[addr + 1 + i]);
+        match target.map(Value::get_value_state) {
+            Some(ValueState::Number(n)) => pending.push(n as usize),
+            Some(ValueState::Register(_)) => {
+                indirect.insert(addr);
+            }
+            _ => (),
+        }
+        if !matches!(opcode, Opcode::Halt | Opcode::Ret | Opcode::Jmp) {
+            pending.push(next);
+        }
+    }
+    (reachable, indirect)
+}
+
+/// The addresses of every instruction that can be reached from the entry
+/// point by following fall through, jumps and calls. Anything left over is
+/// either data or only reached through `indirect_targets`.
+pub fn reachable_instructions(memory: &[Value]) -> BTreeSet<usize> {
+    trace_control_flow(memory).0
+}
+
+/// The reachable jumps and calls whose destination is in a register, so
+/// need looking at by hand to find where they go
+pub fn indirect_targets(memory: &[Value]) -> BTreeSet<usize> {
+    trace_control_flow(memory).1
+}
@@ -0,0 +1,87 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::value::Value;
+
+/// Everything a single instruction changed, recorded as the values it
+/// overwrote so the instruction can be reversed
+#[derive(Debug, Default)]
+pub(crate) struct Undo {
+    pub pc: usize,
+    pub opcode: usize,
+    pub register: Option<(usize, Value)>,
+    pub memory: Option<(usize, Value)>,
+    pub pushed: bool,
+    pub popped: Option<Value>,
+    /// The input consumed, and whether a fresh line was read to get it
+    pub input: Option<(Value, bool)>,
+}
+
+/// What a single instruction did, in the order it did it, as recorded by
+/// `VM::record_steps`. Registers are numbered from 0 and `stack_delta` is how
+/// much deeper the stack ended up.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub pc: usize,
+    pub opcode_num: u16,
+    pub reg_writes: Vec<(usize, u16)>,
+    pub mem_writes: Vec<(usize, u16)>,
+    pub stack_delta: i32,
+    /// The byte written by `out`, if this was one
+    pub output: Option<u8>,
+}
+
+/// A bounded record of recent instructions, oldest first
+pub(crate) struct History {
+    capacity: usize,
+    entries: VecDeque<Undo>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, undo: Undo) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryError {
+    /// `enable_history` hasn't been called
+    Disabled,
+    /// Every recorded instruction has already been undone
+    Empty,
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Disabled => write!(f, "Execution history is not enabled"),
+            Self::Empty => write!(f, "No more history to step back through"),
+        }
+    }
+}
+
+impl Error for HistoryError {}
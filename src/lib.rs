@@ -1,2 +1,9 @@
+pub mod asm;
+pub mod disasm;
+pub mod history;
 pub mod machine;
+pub mod puzzles;
+pub mod save;
+pub mod storage;
+pub mod teleporter;
 pub mod value;
@@ -1,306 +1,2534 @@
-use std::collections::VecDeque;
-use std::fs::File;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::rc::Rc;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
-use text_io::read;
 
-use crate::value::{Value, ValueState};
+use crate::disasm::disassemble_from;
+use crate::history::{History, HistoryError, StepRecord, Undo};
+use crate::save::{SaveError, SaveFormat};
+use crate::storage::{MemoryStorage, Storage};
+use crate::value::{Arch, Value, ValueError, ValueState};
 
-#[derive(Debug)]
+const MEMORY_SIZE: usize = Arch::SYNACOR.math_mod() as usize;
+
+/// The most words `from_bytes` will take, which is the whole address space.
+/// Use `from_bytes_with_max` for a lower limit.
+pub const MAX_PROGRAM_WORDS: usize = MEMORY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
-    Halt,
-    Set,
-    Push,
-    Pop,
-    Eq,
-    Gt,
-    Jmp,
-    Jt,
-    Jf,
-    Add,
-    Mult,
-    Mod,
-    And,
-    Or,
-    Not,
-    Rmem,
-    Wmem,
-    Call,
-    Ret,
-    Out,
-    In,
-    Noop,
+    Halt = 0,
+    Set = 1,
+    Push = 2,
+    Pop = 3,
+    Eq = 4,
+    Gt = 5,
+    Jmp = 6,
+    Jt = 7,
+    Jf = 8,
+    Add = 9,
+    Mult = 10,
+    Mod = 11,
+    And = 12,
+    Or = 13,
+    Not = 14,
+    Rmem = 15,
+    Wmem = 16,
+    Call = 17,
+    Ret = 18,
+    Out = 19,
+    In = 20,
+    Noop = 21,
 }
 
 impl Opcode {
-    fn num_args(&self) -> usize {
+    pub const COUNT: usize = 22;
+
+    /// Every opcode, indexed by its number
+    pub const ALL: [Opcode; Self::COUNT] = [
+        Self::Halt,
+        Self::Set,
+        Self::Push,
+        Self::Pop,
+        Self::Eq,
+        Self::Gt,
+        Self::Jmp,
+        Self::Jt,
+        Self::Jf,
+        Self::Add,
+        Self::Mult,
+        Self::Mod,
+        Self::And,
+        Self::Or,
+        Self::Not,
+        Self::Rmem,
+        Self::Wmem,
+        Self::Call,
+        Self::Ret,
+        Self::Out,
+        Self::In,
+        Self::Noop,
+    ];
+
+    pub fn to_u16(&self) -> u16 {
+        *self as u16
+    }
+
+    /// The instruction's name as given in the spec
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Self::Halt => "halt",
+            Self::Set => "set",
+            Self::Push => "push",
+            Self::Pop => "pop",
+            Self::Eq => "eq",
+            Self::Gt => "gt",
+            Self::Jmp => "jmp",
+            Self::Jt => "jt",
+            Self::Jf => "jf",
+            Self::Add => "add",
+            Self::Mult => "mult",
+            Self::Mod => "mod",
+            Self::And => "and",
+            Self::Or => "or",
+            Self::Not => "not",
+            Self::Rmem => "rmem",
+            Self::Wmem => "wmem",
+            Self::Call => "call",
+            Self::Ret => "ret",
+            Self::Out => "out",
+            Self::In => "in",
+            Self::Noop => "noop",
+        }
+    }
+
+    /// How many operands follow the opcode in memory
+    pub const fn num_args(&self) -> usize {
         match self {
-            Self::Halt | Self::Noop => 0,
-            Self::Push | Self::Pop | Self::Jmp | Self::Call | Self::Ret | Self::Out | Self::In => 1,
+            Self::Halt | Self::Ret | Self::Noop => 0,
+            Self::Push | Self::Pop | Self::Jmp | Self::Call | Self::Out | Self::In => 1,
             Self::Set | Self::Jt | Self::Jf | Self::Not | Self::Rmem | Self::Wmem => 2,
             Self::Eq | Self::Gt | Self::Add | Self::Mult | Self::Mod | Self::And | Self::Or => 3,
         }
     }
 }
 
+/// How many operands the spec gives each opcode, in opcode order
+const SPEC_ARG_COUNTS: [usize; Opcode::COUNT] = [
+    0, 2, 1, 1, 3, 3, 1, 2, 2, 3, 3, 3, 3, 3, 2, 2, 2, 1, 0, 1, 1, 0,
+];
+
+// Refuse to build if the opcode tables ever drift apart from each other or
+// from the spec
+const _: () = {
+    let mut i = 0;
+    while i < Opcode::COUNT {
+        assert!(Opcode::ALL[i] as usize == i, "Opcode::ALL is out of order");
+        assert!(
+            Opcode::ALL[i].num_args() == SPEC_ARG_COUNTS[i],
+            "Opcode::num_args disagrees with the spec"
+        );
+        i += 1;
+    }
+};
+
+impl From<&Opcode> for u16 {
+    fn from(opcode: &Opcode) -> Self {
+        opcode.to_u16()
+    }
+}
+
+/// The mnemonic, so `Opcode::Rmem` prints as `rmem`
+impl Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+impl FromStr for Opcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|opcode| opcode.mnemonic() == s)
+            .ok_or_else(|| format!("Unknown mnemonic {s}"))
+    }
+}
+
+/// Why a value couldn't be decoded as an opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A perfectly good number, but past the last opcode
+    UnknownOpcode(u16),
+    /// A register or an invalid value, where only a number makes sense
+    NotANumber(ValueState),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownOpcode(n) => write!(f, "Unknown opcode {n}"),
+            Self::NotANumber(ValueState::Register(r)) => {
+                write!(f, "Expected an opcode but found register {r}")
+            }
+            Self::NotANumber(_) => write!(f, "Expected an opcode but found an invalid value"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Opcode {
+    /// Works out which opcode `value` is on a machine with the given arch
+    pub fn decode(value: Value, arch: Arch) -> Result<Self, DecodeError> {
+        match arch.value_state(value) {
+            ValueState::Number(n) => Self::ALL
+                .get(n as usize)
+                .copied()
+                .ok_or(DecodeError::UnknownOpcode(n)),
+            state => Err(DecodeError::NotANumber(state)),
+        }
+    }
+}
+
 impl TryFrom<Value> for Opcode {
-    type Error = String;
+    type Error = DecodeError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
-        let value = value.get_value_state();
-        if let ValueState::Number(n) = value {
-            match n {
-                0 => Ok(Self::Halt),
-                1 => Ok(Self::Set),
-                2 => Ok(Self::Push),
-                3 => Ok(Self::Pop),
-                4 => Ok(Self::Eq),
-                5 => Ok(Self::Gt),
-                6 => Ok(Self::Jmp),
-                7 => Ok(Self::Jt),
-                8 => Ok(Self::Jf),
-                9 => Ok(Self::Add),
-                10 => Ok(Self::Mult),
-                11 => Ok(Self::Mod),
-                12 => Ok(Self::And),
-                13 => Ok(Self::Or),
-                14 => Ok(Self::Not),
-                15 => Ok(Self::Rmem),
-                16 => Ok(Self::Wmem),
-                17 => Ok(Self::Call),
-                18 => Ok(Self::Ret),
-                19 => Ok(Self::Out),
-                20 => Ok(Self::In),
-                21 => Ok(Self::Noop),
-                _ => Err(format!("Unknown opcode {n}")),
+        Self::decode(value, Arch::SYNACOR)
+    }
+}
+
+/// What the `in` opcode does with typed lines containing non-ascii bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputCharset {
+    /// Only let ascii through, which is what the challenge expects. What
+    /// happens to anything else is up to the `NonAsciiPolicy`.
+    #[default]
+    AsciiOnly,
+    /// Feed every byte through to the program as-is
+    Raw,
+}
+
+/// What the `out` opcode sends to the output sink
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// Each value as the UTF-8 of the character it stands for, which is
+    /// what a terminal wants
+    #[default]
+    Chars,
+    /// The low byte of each value exactly as it is, for binary-safe capture
+    Bytes,
+}
+
+/// How `InputCharset::AsciiOnly` deals with a line containing non-ascii
+/// characters, such as a pasted smart quote
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NonAsciiPolicy {
+    /// Throw the whole line away, saying which character was the problem
+    #[default]
+    Reject,
+    /// Drop the offending characters and use the rest of the line
+    Strip,
+    /// Turn each offending character into a space
+    ReplaceWithSpace,
+}
+
+/// Everything that can stop the VM partway through an instruction. Each
+/// variant carries the `pc` of the instruction that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    BadOpcode {
+        pc: usize,
+        error: DecodeError,
+    },
+    /// `effective_address` was asked about something other than `rmem` or
+    /// `wmem`
+    UnexpectedOpcode {
+        pc: usize,
+        opcode: Opcode,
+    },
+    AddressOutOfRange {
+        pc: usize,
+        address: usize,
+    },
+    StackUnderflow {
+        pc: usize,
+    },
+    InvalidWrite {
+        pc: usize,
+        target: Value,
+    },
+    BadValue {
+        pc: usize,
+        error: ValueError,
+    },
+    StepLimitExceeded {
+        pc: usize,
+        steps: usize,
+    },
+    OutputFailed {
+        pc: usize,
+        error: String,
+    },
+    DivideByZero {
+        pc: usize,
+    },
+    InputExhausted {
+        pc: usize,
+    },
+    InvalidOperand {
+        pc: usize,
+        operand_index: usize,
+        raw: u16,
+    },
+    StackOverflow {
+        pc: usize,
+    },
+    BadRegister {
+        pc: usize,
+        register: usize,
+    },
+}
+
+impl VmError {
+    pub fn pc(&self) -> usize {
+        match *self {
+            Self::BadOpcode { pc, .. }
+            | Self::UnexpectedOpcode { pc, .. }
+            | Self::AddressOutOfRange { pc, .. }
+            | Self::StackUnderflow { pc }
+            | Self::InvalidWrite { pc, .. }
+            | Self::BadValue { pc, .. }
+            | Self::StepLimitExceeded { pc, .. }
+            | Self::OutputFailed { pc, .. }
+            | Self::DivideByZero { pc }
+            | Self::InputExhausted { pc }
+            | Self::InvalidOperand { pc, .. }
+            | Self::StackOverflow { pc }
+            | Self::BadRegister { pc, .. } => pc,
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error at {}: ", self.pc())?;
+        match self {
+            Self::BadOpcode { error, .. } => error.fmt(f),
+            Self::UnexpectedOpcode { opcode, .. } => {
+                write!(f, "Expected rmem or wmem, found {opcode}")
             }
-        } else {
-            Err(format!("Unexpected value: {value:?}"))
+            Self::AddressOutOfRange { address, .. } => {
+                write!(f, "Address {address} is outside of the address space")
+            }
+            Self::StackUnderflow { .. } => write!(f, "Cannot pop an empty stack"),
+            Self::InvalidWrite { target, .. } => {
+                write!(f, "Attempt to write to invalid memory address {target}")
+            }
+            Self::BadValue { error, .. } => error.fmt(f),
+            Self::StepLimitExceeded { steps, .. } => {
+                write!(f, "Still running after {steps} steps")
+            }
+            Self::OutputFailed { error, .. } => write!(f, "Could not write output: {error}"),
+            Self::DivideByZero { .. } => write!(f, "Attempted to mod by zero"),
+            Self::InputExhausted { .. } => write!(f, "Program wants more input than it was given"),
+            Self::InvalidOperand {
+                operand_index, raw, ..
+            } => write!(f, "Operand {operand_index} is the invalid value {raw}"),
+            Self::StackOverflow { .. } => write!(f, "The stack is too deep"),
+            Self::BadRegister { register, .. } => write!(f, "There is no register {register}"),
+        }
+    }
+}
+
+impl Error for VmError {}
+
+/// Why a program image couldn't be turned into a VM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// Programs are made of 16-bit words, so must have an even length
+    OddLength(usize),
+    /// The program is bigger than the limit it was loaded with, or doesn't
+    /// fit in the address space
+    TooLarge { words: usize, max: usize },
+    /// An instruction given to `from_instructions` has the wrong number of
+    /// operands for its opcode
+    WrongOperandCount { opcode: Opcode, found: usize },
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::OddLength(len) => write!(
+                f,
+                "Program is {len} bytes, which isn't a whole number of words"
+            ),
+            Self::TooLarge { words, max } => {
+                write!(f, "Program is {words} words but can be at most {max} words")
+            }
+            Self::WrongOperandCount { opcode, found } => write!(
+                f,
+                "{opcode} takes {} operands but was given {found}",
+                opcode.num_args()
+            ),
         }
     }
 }
 
+impl Error for LoadError {}
+
+/// `run_headless` failed. What the program printed first comes along too, as
+/// it's usually the best clue to what went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadlessError {
+    pub error: VmError,
+    pub output: String,
+}
+
+impl Display for HeadlessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for HeadlessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// What to do with a line of input once any meta-commands have been handled
+enum LineAction {
+    Feed(Vec<u8>),
+    Skip,
+    Exit,
+}
+
+/// How much of the stack `!dump` shows
+const DUMP_STACK_DEPTH: usize = 8;
+/// How many instructions `!disasm` shows if not told otherwise
+const DISASM_COUNT: usize = 10;
+
+/// Like `eprintln!`, but to the VM's status sink. There's nowhere left to
+/// report it if that fails, so errors are ignored.
+macro_rules! status {
+    ($vm:expr, $($arg:tt)*) => {{
+        let _ = writeln!($vm.status, $($arg)*);
+    }};
+}
+
+const DEFAULT_SAVE: &str = "vm.ron";
+const SAVE_DIR: &str = "saves";
+
+fn save_slot(name: &str) -> String {
+    format!("{SAVE_DIR}/{name}.ron")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExecutionState {
     Running,
-    Complete,
+    Complete(HaltReason),
+    Breakpoint(usize),
+    /// A watched register or address was written to by the last instruction
+    Watchpoint {
+        kind: WatchKind,
+        index: usize,
+        old: Value,
+        new: Value,
+    },
+}
+
+/// Why the program stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    /// The program executed `halt`
+    Halt,
+    /// The program executed `ret` with nothing on the stack
+    EmptyStackReturn,
+    /// There was no more input to give the program
+    EndOfInput,
+    /// The user typed `exit`
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Register,
+    Memory,
+}
+
+/// Called with the pc, opcode and operands of each instruction before it runs
+pub type TraceFn = Box<dyn FnMut(usize, &Opcode, &[Value])>;
+
+/// An instruction as `step_and_report` saw it just before running it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutedInstruction {
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// The operands as they appear in memory
+    pub operands: Vec<Value>,
+    /// The operands with any registers swapped for what they held, which is
+    /// only meaningful for the ones the instruction reads
+    pub resolved: Vec<Value>,
+}
+
+/// Decides whether a conditional breakpoint should stop the machine
+pub type BreakCondition = Box<dyn Fn(&VM) -> bool>;
+
+/// A host peripheral wired onto an address with `map_io`
+pub trait IoHandler {
+    /// Produces the value the program sees when it reads `addr`
+    fn on_read(&mut self, addr: usize) -> Value;
+    /// Takes a value the program wrote to `addr`
+    fn on_write(&mut self, addr: usize, value: Value);
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct VM {
+    memory: Vec<Value>,
+    /// The memory as the program was loaded, for `reset`
+    #[serde(skip)]
+    initial_memory: Vec<Value>,
+    stack: Vec<Value>,
+    /// The return address of every `call` that hasn't returned yet, oldest
+    /// first
+    #[serde(skip)]
+    call_frames: Vec<usize>,
+    registers: [Value; Arch::REGISTER_COUNT],
+    pc: usize,
+    /// What's left of the current line of input, newline and all. This is
+    /// saved too, so a save taken partway through a line picks up from the
+    /// same byte.
+    input: VecDeque<Value>,
+    #[serde(default)]
+    input_log: Vec<String>,
+    #[serde(skip)]
+    input_charset: InputCharset,
+    #[serde(skip)]
+    non_ascii_policy: NonAsciiPolicy,
+    #[serde(skip)]
+    output_encoding: OutputEncoding,
+    #[serde(skip)]
+    arch: Arch,
+    #[serde(skip)]
+    breakpoints: HashSet<usize>,
+    #[serde(skip)]
+    conditional_breakpoints: HashMap<usize, Vec<BreakCondition>>,
+    #[serde(skip)]
+    register_watches: HashSet<usize>,
+    #[serde(skip)]
+    memory_watches: HashSet<usize>,
+    /// The watch the instruction currently executing has tripped, if any
+    #[serde(skip)]
+    watch_hit: Option<ExecutionState>,
+    /// Lines from `preload_input` still to be read, which come before
+    /// anything from `input_source`
+    #[serde(skip)]
+    preloaded: VecDeque<String>,
+    /// Where lines come from, or `None` if there's nothing to read
+    #[serde(skip)]
+    input_source: Option<Box<dyn BufRead>>,
+    /// Whether to show a `> ` before reading a line, for when a person is
+    /// doing the typing
+    #[serde(skip)]
+    prompt: bool,
+    #[serde(skip, default = "default_storage")]
+    storage: Box<dyn Storage>,
+    #[serde(skip, default = "default_output")]
+    output: Box<dyn Write>,
+    /// Where `output` goes while `capture_bytes` is in use, and the encoding
+    /// to go back to once it isn't
+    #[serde(skip)]
+    captured: Option<(SharedBuffer, OutputEncoding)>,
+    /// Where messages from the VM itself go, as opposed to the program
+    #[serde(skip, default = "default_status")]
+    status: Box<dyn Write>,
+    /// Saved along with everything else so totals carry on across a load.
+    /// Saves from before they were saved start again from zero.
+    #[serde(default)]
+    instruction_count: u64,
+    #[serde(default)]
+    opcode_counts: [u64; Opcode::COUNT],
+    #[serde(skip)]
+    history: Option<History>,
+    /// What the instruction currently executing has changed so far
+    #[serde(skip)]
+    undo: Option<Undo>,
+    /// Never prompt for input, see `run_headless`
+    #[serde(skip)]
+    headless: bool,
+    #[serde(skip)]
+    echo_input: bool,
+    #[serde(skip)]
+    write_tracker: Option<WriteTracker>,
+    #[serde(skip)]
+    records: Option<Vec<StepRecord>>,
+    /// What the instruction currently executing has done so far
+    #[serde(skip)]
+    record: Option<StepRecord>,
+    #[serde(skip)]
+    trace: Option<TraceFn>,
+    #[serde(skip, default = "default_max_stack_depth")]
+    max_stack_depth: usize,
+    /// How many more instructions to run before `!step` stops again
+    #[serde(skip)]
+    step_budget: Option<usize>,
+    /// Why the program last stopped, if it has
+    #[serde(skip)]
+    halt_reason: Option<HaltReason>,
+    #[serde(skip)]
+    io: HashMap<usize, Box<dyn IoHandler>>,
+}
+
+/// Which addresses have been run as code and which have been overwritten
+#[derive(Default)]
+struct WriteTracker {
+    executed: HashSet<usize>,
+    written: HashSet<usize>,
+}
+
+/// A copy of everything the program can see, for rolling back to later
+/// without going through a save file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSnapshot {
     memory: Vec<Value>,
     stack: Vec<Value>,
-    registers: [Value; 8],
+    registers: [Value; Arch::REGISTER_COUNT],
     pc: usize,
     input: VecDeque<Value>,
 }
 
+/// Words compared at a time by `VmSnapshot::diff`, so unchanged stretches
+/// of memory are skipped in bulk
+const DIFF_CHUNK: usize = 256;
+
+impl VmSnapshot {
+    /// What changed between this snapshot and `other`, taking this one as
+    /// the before
+    pub fn diff(&self, other: &VmSnapshot) -> MemoryDiff {
+        let mut memory = Vec::new();
+        let chunks = self
+            .memory
+            .chunks(DIFF_CHUNK)
+            .zip(other.memory.chunks(DIFF_CHUNK));
+        for (i, (old, new)) in chunks.enumerate() {
+            if old == new {
+                continue;
+            }
+            memory.extend(
+                old.iter()
+                    .zip(new)
+                    .enumerate()
+                    .filter(|(_, (old, new))| old != new)
+                    .map(|(offset, (&old, &new))| (i * DIFF_CHUNK + offset, old, new)),
+            );
+        }
+        let registers = self
+            .registers
+            .iter()
+            .zip(&other.registers)
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(i, (&old, &new))| (i, old, new))
+            .collect();
+        let common = self
+            .stack
+            .iter()
+            .zip(&other.stack)
+            .take_while(|(old, new)| old == new)
+            .count();
+        MemoryDiff {
+            memory,
+            registers,
+            popped: self.stack[common..].to_vec(),
+            pushed: other.stack[common..].to_vec(),
+        }
+    }
+}
+
+/// The differences between two snapshots, as found by `VmSnapshot::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryDiff {
+    /// `(address, old, new)` for every word that changed, in address order
+    pub memory: Vec<(usize, Value, Value)>,
+    /// `(register, old, new)` for every register that changed
+    pub registers: Vec<(usize, Value, Value)>,
+    /// What was on the stack above the part both snapshots share, bottom first
+    pub popped: Vec<Value>,
+    /// What's on the stack above the shared part now, bottom first
+    pub pushed: Vec<Value>,
+}
+
+impl MemoryDiff {
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+            && self.registers.is_empty()
+            && self.popped.is_empty()
+            && self.pushed.is_empty()
+    }
+}
+
+/// Output that can still be read after being handed to the VM
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn into_string(self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sets up a VM in a particular state, such as partway through a routine
+#[derive(Debug, Clone, Default)]
+pub struct VmBuilder {
+    memory: Vec<u16>,
+    registers: Vec<(usize, u16)>,
+    pc: usize,
+    stack: Vec<u16>,
+    input: String,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn memory(mut self, memory: Vec<u16>) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    pub fn register(mut self, register: usize, value: u16) -> Self {
+        self.registers.push((register, value));
+        self
+    }
+
+    pub fn pc(mut self, pc: usize) -> Self {
+        self.pc = pc;
+        self
+    }
+
+    pub fn push_stack(mut self, value: u16) -> Self {
+        self.stack.push(value);
+        self
+    }
+
+    /// Queues up text for the program to read before anything is typed
+    pub fn input(mut self, input: &str) -> Self {
+        self.input.push_str(input);
+        self
+    }
+
+    /// Panics if the memory doesn't fit or any of the registers, values or
+    /// the pc don't make sense
+    pub fn build(self) -> VM {
+        let mut vm = VM::new(self.memory).unwrap_or_else(|err| panic!("{err}"));
+        let register_base = vm.arch.register_base();
+        for (register, value) in self.registers {
+            assert!(
+                register < Arch::REGISTER_COUNT,
+                "There is no register {register}"
+            );
+            assert!(value < register_base, "{value} is not a valid number");
+            vm.registers[register] = Value::mew(value);
+        }
+        for value in self.stack {
+            assert!(value < register_base, "{value} is not a valid number");
+            vm.stack.push(Value::mew(value));
+        }
+        assert!(
+            self.pc < vm.arch.math_mod() as usize,
+            "pc {} is outside of the address space",
+            self.pc
+        );
+        vm.pc = self.pc;
+        vm.input
+            .extend(self.input.bytes().map(|b| Value::mew(b as u16)));
+        vm
+    }
+}
+
+/// Gives the program the whole address space to play with up front
+fn pad_memory(memory: Vec<u16>, arch: Arch) -> Result<Vec<Value>, LoadError> {
+    let max = arch.address_space();
+    if memory.len() > max {
+        return Err(LoadError::TooLarge {
+            words: memory.len(),
+            max,
+        });
+    }
+    let mut memory: Vec<Value> = memory.into_iter().map(Value::mew).collect();
+    memory.resize(max, Value::mew(0));
+    Ok(memory)
+}
+
+/// What goes in the memory past the end of the loaded program
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFill {
+    /// What the spec says, and what programs tend to rely on without meaning
+    /// to
+    #[default]
+    Zero,
+    /// Numbers from a pseudo-random sequence, the same every time for the
+    /// same seed, for shaking out reads of memory that was never written
+    Seeded(u64),
+}
+
+impl MemoryFill {
+    fn fill(self, memory: &mut [Value], arch: Arch) {
+        let Self::Seeded(mut state) = self else {
+            return;
+        };
+        for word in memory {
+            // splitmix64, which is plenty random enough for this
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            // Only numbers, so it all still works as operands
+            *word = Value::mew(z as u16 & arch.math_mask());
+        }
+    }
+}
+
+/// Drops the frame a `ret` to `addr` returned from, along with any the
+/// program abandoned on the way. A `ret` that isn't back to any caller, such
+/// as a computed jump, leaves the frames alone.
+fn return_to_frame(frames: &mut Vec<usize>, addr: usize) {
+    if let Some(i) = frames.iter().rposition(|&frame| frame == addr) {
+        frames.truncate(i);
+    }
+}
+
+/// Deep enough for any sane program, but stops runaway recursion long before
+/// memory runs out
+const DEFAULT_MAX_STACK_DEPTH: usize = 100_000;
+
+fn default_max_stack_depth() -> usize {
+    DEFAULT_MAX_STACK_DEPTH
+}
+
+/// Nothing is wired up to the terminal or filesystem unless the embedder
+/// does it with the setters below, so the VM can run anywhere
+fn default_output() -> Box<dyn Write> {
+    Box::new(io::sink())
+}
+
+fn default_status() -> Box<dyn Write> {
+    Box::new(io::sink())
+}
+
+fn default_storage() -> Box<dyn Storage> {
+    Box::new(MemoryStorage::default())
+}
+
 impl VM {
-    pub fn new(memory: Vec<u16>) -> Self {
-        Self {
-            memory: memory.into_iter().map(Value::mew).collect(),
+    pub fn new(memory: Vec<u16>) -> Result<Self, LoadError> {
+        Self::with_arch(memory, Arch::default())
+    }
+
+    /// Loads a program from the little-endian binary format the challenge is
+    /// distributed in
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        Self::from_bytes_with_max(bytes, MAX_PROGRAM_WORDS)
+    }
+
+    /// Like `from_bytes`, but turns away programs longer than `max_words`,
+    /// for when the image comes from somewhere that can't be trusted. A
+    /// limit past the address space has no effect.
+    pub fn from_bytes_with_max(bytes: &[u8], max_words: usize) -> Result<Self, LoadError> {
+        if !bytes.len().is_multiple_of(2) {
+            return Err(LoadError::OddLength(bytes.len()));
+        }
+        // Before decoding anything, so a huge image doesn't get copied first
+        let words = bytes.len() / 2;
+        if words > max_words {
+            return Err(LoadError::TooLarge {
+                words,
+                max: max_words,
+            });
+        }
+        let memory = bytes
+            .chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        Self::new(memory)
+    }
+
+    /// Like `new`, but with the memory past the end of the program filled in
+    /// according to `fill`
+    pub fn new_with_fill(memory: Vec<u16>, fill: MemoryFill) -> Result<Self, LoadError> {
+        let program_len = memory.len();
+        let mut vm = Self::new(memory)?;
+        fill.fill(&mut vm.memory[program_len..], vm.arch);
+        vm.set_initial_memory();
+        Ok(vm)
+    }
+
+    /// Runs the program on a machine with different word sizes to the one
+    /// described in the spec. Fails if the program doesn't fit in the
+    /// machine's memory.
+    pub fn with_arch(memory: Vec<u16>, arch: Arch) -> Result<Self, LoadError> {
+        let memory = pad_memory(memory, arch)?;
+        Ok(Self {
+            initial_memory: memory.clone(),
+            memory,
             stack: Vec::new(),
-            registers: [Value::mew(0); 8],
+            call_frames: Vec::new(),
+            registers: [Value::mew(0); Arch::REGISTER_COUNT],
             pc: 0,
             input: VecDeque::new(),
-        }
+            input_log: Vec::new(),
+            input_charset: InputCharset::default(),
+            non_ascii_policy: NonAsciiPolicy::default(),
+            output_encoding: OutputEncoding::default(),
+            arch,
+            breakpoints: HashSet::new(),
+            conditional_breakpoints: HashMap::new(),
+            register_watches: HashSet::new(),
+            memory_watches: HashSet::new(),
+            watch_hit: None,
+            preloaded: VecDeque::new(),
+            input_source: None,
+            prompt: false,
+            storage: default_storage(),
+            output: default_output(),
+            captured: None,
+            status: default_status(),
+            instruction_count: 0,
+            opcode_counts: [0; Opcode::COUNT],
+            history: None,
+            undo: None,
+            headless: false,
+            echo_input: false,
+            write_tracker: None,
+            records: None,
+            record: None,
+            trace: None,
+            max_stack_depth: DEFAULT_MAX_STACK_DEPTH,
+            step_budget: None,
+            halt_reason: None,
+            io: HashMap::new(),
+        })
     }
 
-    fn get_instruction(&self) -> Result<Opcode, String> {
-        self.memory[self.pc].try_into()
+    /// Sends everything the program outputs to `output` instead of nowhere
+    pub fn with_output(memory: Vec<u16>, output: impl Write + 'static) -> Result<Self, LoadError> {
+        Ok(Self {
+            output: Box::new(output),
+            ..Self::new(memory)?
+        })
     }
 
-    fn get_memory(&self, offset: usize) -> Value {
-        self.memory[self.pc + offset]
+    /// Sends the program's output to `output`, ending any `capture_bytes`
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+        if let Some((_, encoding)) = self.captured.take() {
+            self.output_encoding = encoding;
+        }
+    }
+
+    pub fn set_output_encoding(&mut self, encoding: OutputEncoding) {
+        self.output_encoding = encoding;
     }
 
-    fn set_memory(&mut self, target: Value, value: Value) {
-        match target.get_value_state() {
-            ValueState::Number(n) => self.memory[n as usize] = value,
-            ValueState::Register(r) => self.registers[r] = value,
-            ValueState::Invalid => panic!("Attempt to write to invalid memory address {target}"),
+    /// Returns the exact bytes the program has output since the last call.
+    /// The first call switches the output over to an internal buffer with
+    /// `OutputEncoding::Bytes`, so it returns nothing, and it keeps
+    /// capturing until the output is changed with `set_output`, which puts
+    /// the encoding back how it was.
+    pub fn capture_bytes(&mut self) -> Vec<u8> {
+        match &self.captured {
+            Some((buffer, _)) => mem::take(&mut *buffer.0.borrow_mut()),
+            None => {
+                let buffer = SharedBuffer::default();
+                self.output = Box::new(buffer.clone());
+                let encoding = mem::replace(&mut self.output_encoding, OutputEncoding::Bytes);
+                self.captured = Some((buffer, encoding));
+                Vec::new()
+            }
         }
     }
 
-    fn get_value(&self, offset: usize) -> Value {
-        let v = self.get_memory(offset);
-        match v.get_value_state() {
-            ValueState::Register(i) => self.registers[i],
-            // Just gonna return invalid values because why not
-            _ => v,
+    /// Sends the VM's own messages (save confirmations, debugging commands
+    /// and so on) to `status` instead of nowhere
+    pub fn set_status(&mut self, status: impl Write + 'static) {
+        self.status = Box::new(status);
+    }
+
+    /// Keeps save files and scripts in `storage` instead of in memory
+    pub fn set_storage(&mut self, storage: impl Storage + 'static) {
+        self.storage = Box::new(storage);
+    }
+
+    /// Reads lines for the `in` opcode from `input`. Once it runs dry the
+    /// program is stopped as if it had exited.
+    pub fn with_input(memory: Vec<u16>, input: impl BufRead + 'static) -> Result<Self, LoadError> {
+        Ok(Self {
+            input_source: Some(Box::new(input)),
+            ..Self::new(memory)?
+        })
+    }
+
+    /// Reads lines for the `in` opcode from `input`, as `with_input` does
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input_source = Some(Box::new(input));
+    }
+
+    /// Shows a `> ` before each line is read from the input source, for
+    /// when a person is doing the typing
+    pub fn set_prompt(&mut self, prompt: bool) {
+        self.prompt = prompt;
+    }
+
+    pub fn memory(&self) -> &[Value] {
+        &self.memory
+    }
+
+    pub fn registers(&self) -> &[Value; Arch::REGISTER_COUNT] {
+        &self.registers
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn peek_memory(&self, addr: usize) -> Option<Value> {
+        self.memory.get(addr).copied()
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// How many times each opcode has run, indexed by opcode number
+    pub fn opcode_counts(&self) -> &[u64; Opcode::COUNT] {
+        &self.opcode_counts
+    }
+
+    /// Zeroes the instruction counters, for measuring from a particular
+    /// point. Loading a save replaces them with the counts it was saved with.
+    pub fn reset_counters(&mut self) {
+        self.instruction_count = 0;
+        self.opcode_counts = [0; Opcode::COUNT];
+    }
+
+    pub fn set_input_charset(&mut self, charset: InputCharset) {
+        self.input_charset = charset;
+    }
+
+    pub fn set_non_ascii_policy(&mut self, policy: NonAsciiPolicy) {
+        self.non_ascii_policy = policy;
+    }
+
+    /// Writes each line read from the input source to the output
+    /// once it's been read, so a session with its input redirected from a
+    /// file reads like it was typed. The program is given the same bytes
+    /// either way. Lines queued up with `preload_input` and the like, which
+    /// don't come from the input source, are never echoed.
+    pub fn set_echo_input(&mut self, echo: bool) {
+        self.echo_input = echo;
+    }
+
+    /// Starts from a fully zeroed address space with only the listed cells set
+    pub fn with_sparse_memory(entries: &[(usize, u16)]) -> Self {
+        let mut memory = vec![0; MEMORY_SIZE];
+        for &(addr, value) in entries {
+            assert!(addr < MEMORY_SIZE, "Address {addr} is outside of memory");
+            memory[addr] = value;
         }
+        Self::new(memory).expect("A full address space always fits")
     }
 
-    pub fn step(&mut self) -> ExecutionState {
-        let opcode = self.get_instruction();
-        match opcode {
-            Ok(opcode) => {
-                match opcode {
-                    Opcode::Halt => return ExecutionState::Complete,
-                    Opcode::Set => {
-                        let target = self.get_memory(1).to_register();
-                        let value = self.get_value(2);
-                        self.registers[target] = value;
-                    }
-                    Opcode::Push => {
-                        let value = self.get_value(1);
-                        self.stack.push(value);
-                    }
-                    Opcode::Pop => {
-                        let value = self.stack.pop().expect("Cannot pop an empty stack");
-                        let target = self.get_memory(1);
-                        self.set_memory(target, value);
-                    }
-                    Opcode::Eq => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        let value = if a == b { 1 } else { 0 };
-                        self.set_memory(target, Value::mew(value));
-                    }
-                    Opcode::Gt => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        let value = if a > b { 1 } else { 0 };
-                        self.set_memory(target, Value::mew(value));
-                    }
-                    Opcode::Jmp => {
-                        self.pc = self.get_value(1).to_number() as usize;
-                        // Avoid updating the pc
-                        return ExecutionState::Running;
-                    }
-                    Opcode::Jt => {
-                        let value = self.get_value(1).to_number();
-                        if value != 0 {
-                            self.pc = self.get_value(2).to_number() as usize;
-                            return ExecutionState::Running;
-                        }
-                    }
-                    Opcode::Jf => {
-                        let value = self.get_value(1).to_number();
-                        if value == 0 {
-                            self.pc = self.get_value(2).to_number() as usize;
-                            return ExecutionState::Running;
-                        }
-                    }
-                    Opcode::Add => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        self.set_memory(target, a + b);
-                    }
-                    Opcode::Mult => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        self.set_memory(target, a * b);
-                    }
-                    Opcode::Mod => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        self.set_memory(target, a % b);
-                    }
-                    Opcode::And => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        self.set_memory(target, a & b);
-                    }
-                    Opcode::Or => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        let b = self.get_value(3);
-                        self.set_memory(target, a | b);
-                    }
-                    Opcode::Not => {
-                        let target = self.get_memory(1);
-                        let a = self.get_value(2);
-                        self.set_memory(target, !a);
-                    }
-                    Opcode::Rmem => {
-                        let target = self.get_memory(1);
-                        let location = self.get_value(2).to_number() as usize;
-                        let value = self.memory[location];
-                        self.set_memory(target, value);
-                    }
-                    Opcode::Wmem => {
-                        let location = self.get_value(1).to_number() as usize;
-                        let value = self.get_value(2);
-                        self.memory[location] = value;
-                    }
-                    Opcode::Call => {
-                        let a = self.get_value(1);
-                        self.stack.push(Value::mew((self.pc + 2) as u16));
-                        self.pc = a.to_number() as usize;
-                        return ExecutionState::Running;
-                    }
-                    Opcode::Ret => {
-                        if let Some(value) = self.stack.pop() {
-                            self.pc = value.to_number() as usize;
-                            return ExecutionState::Running;
-                        } else {
-                            return ExecutionState::Complete;
-                        }
-                    }
-                    Opcode::Out => {
-                        print!("{}", self.get_value(1).to_ascii());
-                    }
-                    Opcode::In => {
-                        if self.input.is_empty() {
-                            print!("> ");
-                            let mut line: String = read!("{}\n");
-                            match line.as_str() {
-                                "save" => {
-                                    let file = File::options()
-                                        .create(true)
-                                        .truncate(true)
-                                        .write(true)
-                                        .open("vm.ron")
-                                        .unwrap();
-                                    ron::ser::to_writer_pretty(file, &self, Default::default())
-                                        .unwrap();
-                                    println!("=== State Saved ===");
-                                    return ExecutionState::Running;
-                                }
-                                "load" => {
-                                    let file =
-                                        File::open("vm.ron").expect("Save file doesn't exist!");
-                                    *self = ron::de::from_reader(&file).unwrap();
-                                    println!("=== State Loaded ===");
-                                    line = "look".to_owned();
-                                }
-                                "exit" => {
-                                    return ExecutionState::Complete;
-                                }
-                                line if !line.is_ascii() => {
-                                    println!("Cannot use non-ascii input!");
-                                    return ExecutionState::Running;
-                                }
-                                _ => (),
-                            }
-                            self.input
-                                .extend(line.bytes().map(|b| Value::mew(b as u16)));
-                            const MEWLINE: Value = Value::mew(('\n' as u32) as u16);
-                            self.input.push_back(MEWLINE);
-                        }
-                        let value = self.input.pop_front().unwrap();
-                        let target = self.get_memory(1);
-                        self.set_memory(target, value);
-                    }
-                    Opcode::Noop => (),
-                }
-                self.pc += opcode.num_args() + 1;
-            }
-            Err(msg) => {
-                eprintln!("Error at {}: {}", self.pc, msg);
-                self.pc += 1;
+    /// Assembles a program from opcodes and their raw operands, for poking at
+    /// individual instructions without hand-building a memory vector
+    pub fn from_instructions(instructions: &[(Opcode, &[u16])]) -> Result<Self, LoadError> {
+        let mut memory = Vec::new();
+        for &(opcode, args) in instructions {
+            if args.len() != opcode.num_args() {
+                return Err(LoadError::WrongOperandCount {
+                    opcode,
+                    found: args.len(),
+                });
             }
+            memory.push(opcode.to_u16());
+            memory.extend_from_slice(args);
+        }
+        Self::new(memory)
+    }
+
+    /// Takes on the state of a loaded save, keeping everything that isn't
+    /// saved (configuration, debugging aids) as it is
+    fn restore_state(&mut self, loaded: VM) {
+        *self = VM {
+            initial_memory: mem::take(&mut self.initial_memory),
+            input_charset: self.input_charset,
+            non_ascii_policy: self.non_ascii_policy,
+            echo_input: self.echo_input,
+            output_encoding: self.output_encoding,
+            captured: self.captured.take(),
+            arch: self.arch,
+            breakpoints: mem::take(&mut self.breakpoints),
+            conditional_breakpoints: mem::take(&mut self.conditional_breakpoints),
+            register_watches: mem::take(&mut self.register_watches),
+            memory_watches: mem::take(&mut self.memory_watches),
+            preloaded: mem::take(&mut self.preloaded),
+            input_source: self.input_source.take(),
+            prompt: self.prompt,
+            storage: mem::replace(&mut self.storage, default_storage()),
+            output: mem::replace(&mut self.output, Box::new(io::sink())),
+            status: mem::replace(&mut self.status, Box::new(io::sink())),
+            // The recorded changes don't apply to the loaded state
+            history: self.history.take().map(|mut history| {
+                history.clear();
+                history
+            }),
+            write_tracker: self.write_tracker.take(),
+            records: self.records.take(),
+            trace: self.trace.take(),
+            max_stack_depth: self.max_stack_depth,
+            step_budget: self.step_budget,
+            io: mem::take(&mut self.io),
+            ..loaded
+        };
+        self.call_frames = self.call_stack();
+    }
+
+    /// Queues up every line of `text` to be read before the input source, so
+    /// the prompt only appears once the program has read all of it. Each
+    /// line is taken as it's needed and treated exactly as if it had been
+    /// typed then, meta-commands and all, so a script can `save` partway
+    /// through.
+    pub fn preload_input(&mut self, text: &str) {
+        self.preloaded.extend(text.lines().map(str::to_owned));
+    }
+
+    /// Swaps in a new program image and starts it from the top, leaving the
+    /// registers, stack and pending input as they are. Handy for trying out
+    /// a patched binary against state you've already set up. A program too
+    /// large for memory is turned away and the old one left in place.
+    pub fn reload_program(&mut self, memory: Vec<u16>) -> Result<(), LoadError> {
+        self.memory = pad_memory(memory, self.arch)?;
+        self.initial_memory.clone_from(&self.memory);
+        self.pc = 0;
+        self.call_frames = self.call_stack();
+        // The recorded changes were made to the old program
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+
+    /// Starts the program again from scratch, with the memory as it was
+    /// loaded and everything else as a new VM would have it: no registers,
+    /// stack, pending input or counts. Configuration and debugging aids are
+    /// left alone, apart from the history, which no longer applies.
+    pub fn reset(&mut self) {
+        self.memory.clone_from(&self.initial_memory);
+        self.stack.clear();
+        self.call_frames.clear();
+        self.registers = [Value::mew(0); Arch::REGISTER_COUNT];
+        self.pc = 0;
+        self.input.clear();
+        self.preloaded.clear();
+        self.input_log.clear();
+        self.halt_reason = None;
+        self.reset_counters();
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Pads the memory out to the whole address space, as `with_arch` does,
+    /// for a VM whose memory came from somewhere else such as a save
+    pub(crate) fn fill_address_space(&mut self) {
+        let size = (self.arch.math_mod() as usize).max(self.memory.len());
+        self.memory.resize(size, Value::mew(0));
+    }
+
+    /// Makes the memory as it is now what `reset` goes back to, for a VM
+    /// that didn't start out from a program image
+    pub(crate) fn set_initial_memory(&mut self) {
+        self.initial_memory.clone_from(&self.memory);
+    }
+
+    /// A fingerprint of everything the program can see, for spotting when two
+    /// runs have ended up in the same place. `DefaultHasher::new` always uses
+    /// the same keys, so this is the same from one run to the next.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.memory.hash(&mut hasher);
+        self.registers.hash(&mut hasher);
+        self.stack.hash(&mut hasher);
+        self.pc.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            memory: self.memory.clone(),
+            stack: self.stack.clone(),
+            registers: self.registers,
+            pc: self.pc,
+            input: self.input.clone(),
         }
-        ExecutionState::Running
     }
 
-    pub fn run(&mut self) {
-        while let ExecutionState::Running = self.step() {}
+    /// Puts the machine back how it was when `snapshot` was taken. Like
+    /// loading a save, this leaves the configuration alone but forgets any
+    /// history, as it no longer applies.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.memory.clone_from(&snapshot.memory);
+        self.stack.clone_from(&snapshot.stack);
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.input.clone_from(&snapshot.input);
+        self.call_frames = self.call_stack();
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    fn get_instruction(&self) -> Result<Opcode, VmError> {
+        self.decode_at(self.pc).map(|(opcode, _)| opcode)
+    }
+
+    /// Makes sure every operand of the instruction at the current pc is a
+    /// number or a register before any of it runs. Operands are numbered
+    /// from 0.
+    fn validate_operands(&self, opcode: Opcode) -> Result<(), VmError> {
+        for operand_index in 0..opcode.num_args() {
+            let operand = self.get_memory(operand_index + 1)?;
+            if let ValueState::Invalid = self.arch.value_state(operand) {
+                return Err(VmError::InvalidOperand {
+                    pc: self.pc,
+                    operand_index,
+                    raw: operand.raw(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the instruction at `addr` without running it, returning the
+    /// opcode and how many operands follow it
+    pub fn decode_at(&self, addr: usize) -> Result<(Opcode, usize), VmError> {
+        let opcode = Opcode::decode(self.read_memory(addr)?, self.arch)
+            .map_err(|error| VmError::BadOpcode { pc: addr, error })?;
+        Ok((opcode, opcode.num_args()))
+    }
+
+    fn check_address(&self, address: usize) -> Result<(), VmError> {
+        if address < self.arch.math_mod() as usize {
+            Ok(())
+        } else {
+            Err(VmError::AddressOutOfRange {
+                pc: self.pc,
+                address,
+            })
+        }
+    }
+
+    /// Anything past the end of the loaded program is uninitialised memory,
+    /// which reads as zero
+    fn read_memory(&self, address: usize) -> Result<Value, VmError> {
+        self.check_address(address)?;
+        Ok(self.memory.get(address).copied().unwrap_or(Value::mew(0)))
+    }
+
+    /// Like `read_memory`, but asks the handler for mapped addresses
+    fn read_mapped(&mut self, address: usize) -> Result<Value, VmError> {
+        self.check_address(address)?;
+        match self.io.get_mut(&address) {
+            Some(handler) => Ok(handler.on_read(address)),
+            None => self.read_memory(address),
+        }
+    }
+
+    fn write_memory(&mut self, address: usize, value: Value) -> Result<(), VmError> {
+        self.check_address(address)?;
+        if let Some(handler) = self.io.get_mut(&address) {
+            handler.on_write(address, value);
+            return Ok(());
+        }
+        if address >= self.memory.len() {
+            self.memory.resize(address + 1, Value::mew(0));
+        }
+        let old = mem::replace(&mut self.memory[address], value);
+        if let Some(undo) = &mut self.undo {
+            undo.memory = Some((address, old));
+        }
+        if let Some(tracker) = &mut self.write_tracker {
+            tracker.written.insert(address);
+        }
+        if let Some(record) = &mut self.record {
+            record.mem_writes.push((address, value.raw()));
+        }
+        if self.memory_watches.contains(&address) {
+            self.watch_hit = Some(ExecutionState::Watchpoint {
+                kind: WatchKind::Memory,
+                index: address,
+                old,
+                new: value,
+            });
+        }
+        Ok(())
+    }
+
+    /// The bytes `out` sends to the output for `value`
+    fn encode_output(&self, value: Value) -> Vec<u8> {
+        match self.output_encoding {
+            OutputEncoding::Chars => value.to_char().to_string().into_bytes(),
+            // Anything over 255 loses its high bits
+            OutputEncoding::Bytes => vec![value.raw() as u8],
+        }
+    }
+
+    fn reg(&self, register: usize) -> Result<Value, VmError> {
+        self.registers
+            .get(register)
+            .copied()
+            .ok_or(VmError::BadRegister {
+                pc: self.pc,
+                register,
+            })
+    }
+
+    /// Direct access to a register, with no watchpoints or history involved,
+    /// or an error rather than a panic if there's no such register
+    pub fn reg_mut(&mut self, register: usize) -> Result<&mut Value, VmError> {
+        let pc = self.pc;
+        self.registers
+            .get_mut(register)
+            .ok_or(VmError::BadRegister { pc, register })
+    }
+
+    fn set_register(&mut self, register: usize, value: Value) -> Result<(), VmError> {
+        let old = mem::replace(self.reg_mut(register)?, value);
+        if let Some(undo) = &mut self.undo {
+            undo.register = Some((register, old));
+        }
+        if let Some(record) = &mut self.record {
+            record.reg_writes.push((register, value.raw()));
+        }
+        if self.register_watches.contains(&register) {
+            self.watch_hit = Some(ExecutionState::Watchpoint {
+                kind: WatchKind::Register,
+                index: register,
+                old,
+                new: value,
+            });
+        }
+        Ok(())
+    }
+
+    fn push_stack(&mut self, value: Value) -> Result<(), VmError> {
+        if self.stack.len() >= self.max_stack_depth {
+            return Err(VmError::StackOverflow { pc: self.pc });
+        }
+        self.stack.push(value);
+        if let Some(undo) = &mut self.undo {
+            undo.pushed = true;
+        }
+        Ok(())
+    }
+
+    fn pop_stack(&mut self) -> Option<Value> {
+        let value = self.stack.pop();
+        if let Some(undo) = &mut self.undo {
+            undo.popped = value;
+        }
+        value
+    }
+
+    fn get_memory(&self, offset: usize) -> Result<Value, VmError> {
+        self.read_memory(self.pc + offset)
+    }
+
+    fn set_memory(&mut self, target: Value, value: Value) -> Result<(), VmError> {
+        match self.arch.value_state(target) {
+            ValueState::Number(n) => self.write_memory(n as usize, value)?,
+            ValueState::Register(r) => self.set_register(r, value)?,
+            ValueState::Invalid => {
+                return Err(VmError::InvalidWrite {
+                    pc: self.pc,
+                    target,
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, v: Value) -> Result<Value, VmError> {
+        match self.arch.value_state(v) {
+            ValueState::Register(i) => self.reg(i),
+            // Just gonna return invalid values because why not
+            _ => Ok(v),
+        }
+    }
+
+    fn get_value(&self, offset: usize) -> Result<Value, VmError> {
+        self.resolve(self.get_memory(offset)?)
+    }
+
+    fn get_number(&self, offset: usize) -> Result<u16, VmError> {
+        self.arch
+            .try_to_number(self.get_value(offset)?)
+            .map_err(|error| self.bad_value(error))
+    }
+
+    fn bad_value(&self, error: ValueError) -> VmError {
+        VmError::BadValue { pc: self.pc, error }
+    }
+
+    /// Works out which address the `rmem` or `wmem` at `pc` would access given
+    /// the current register values
+    pub fn effective_address(&self, pc: usize) -> Result<usize, VmError> {
+        let read = |address: usize| {
+            self.read_memory(address)
+                .map_err(|_| VmError::AddressOutOfRange { pc, address })
+        };
+        let opcode = Opcode::decode(read(pc)?, self.arch)
+            .map_err(|error| VmError::BadOpcode { pc, error })?;
+        let operand = match opcode {
+            Opcode::Rmem => read(pc + 2)?,
+            Opcode::Wmem => read(pc + 1)?,
+            opcode => return Err(VmError::UnexpectedOpcode { pc, opcode }),
+        };
+        self.arch
+            .try_to_number(self.resolve(operand)?)
+            .map(usize::from)
+            .map_err(|error| VmError::BadValue { pc, error })
+    }
+
+    /// Fetches the next line of input, or `None` if the input source has run
+    /// out. This is bytes rather than a `String` so that raw input can have
+    /// anything in it.
+    fn read_line(&mut self) -> Option<Vec<u8>> {
+        if let Some(line) = self.preloaded.pop_front() {
+            return Some(line.into_bytes());
+        }
+        let mut line = Vec::new();
+        // Failing to prompt shouldn't stop us reading what gets typed
+        if self.prompt {
+            let _ = write!(self.output, "> ");
+        }
+        let _ = self.output.flush();
+        let source = self.input_source.as_mut()?;
+        match source.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                while let Some(b'\r' | b'\n') = line.last() {
+                    line.pop();
+                }
+                if self.echo_input {
+                    let _ = self.output.write_all(&line);
+                    let _ = writeln!(self.output);
+                }
+                Some(line)
+            }
+            Err(err) => {
+                status!(self, "Could not read input: {err}");
+                None
+            }
+        }
+    }
+
+    /// Deals with any meta-commands in a line of input before the program
+    /// gets to see it
+    fn handle_line(&mut self, line: String) -> LineAction {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["save"] => self.save(DEFAULT_SAVE),
+            ["save", name] => self.save(&save_slot(name)),
+            ["load"] => return self.load(DEFAULT_SAVE),
+            ["load", name] => return self.load(&save_slot(name)),
+            ["list", "saves"] => self.list_saves(),
+            ["exit"] => return LineAction::Exit,
+            // Debugging commands start with a `!` so they can't be mistaken
+            // for anything the game understands
+            ["!dump"] => self.dump_state(),
+            ["!regs"] => self.print_registers(),
+            ["!disasm"] => self.disasm_command(DISASM_COUNT),
+            ["!disasm", count] => match count.parse() {
+                Ok(count) => self.disasm_command(count),
+                Err(_) => status!(self, "{count} is not a number of instructions"),
+            },
+            ["!setreg", register, value] => self.set_register_command(register, value),
+            ["!peek", addr] => self.peek_command(addr),
+            ["!poke", addr, value] => self.poke_command(addr, value),
+            ["!step"] => self.step_command("1"),
+            ["!step", count] => self.step_command(count),
+            ["!continue"] => self.step_budget = None,
+            ["dumpscript", ..] => {
+                // Not from `words`, so paths can have spaces in
+                let path = line
+                    .trim_start()
+                    .strip_prefix("dumpscript")
+                    .unwrap_or_default()
+                    .trim();
+                let script: String = self.input_log.iter().map(|l| format!("{l}\n")).collect();
+                match self.storage.write(path, script.as_bytes()) {
+                    Ok(()) => status!(self, "=== Script Written ==="),
+                    Err(err) => status!(self, "Could not write script: {err}"),
+                }
+            }
+            _ => return self.check_charset(line.into_bytes()),
+        }
+        LineAction::Skip
+    }
+
+    /// Applies the `InputCharset` to a line the program is about to be given
+    fn check_charset(&mut self, line: Vec<u8>) -> LineAction {
+        if self.input_charset == InputCharset::AsciiOnly && !line.is_ascii() {
+            self.filter_non_ascii(line)
+        } else {
+            LineAction::Feed(line)
+        }
+    }
+
+    /// Gives the program a line exactly as if it had been typed, newline and
+    /// all, ready for its next `in`. Meta-commands aren't picked out, but the
+    /// `InputCharset` and `NonAsciiPolicy` apply, so the line can be turned
+    /// away.
+    pub fn push_input_line(&mut self, line: &str) {
+        if let LineAction::Feed(line) = self.check_charset(line.as_bytes().to_vec()) {
+            self.queue_line(line);
+        }
+    }
+
+    /// Adds a line and its newline to the input, logging it for `dumpscript`
+    fn queue_line(&mut self, line: Vec<u8>) {
+        const MEWLINE: Value = Value::mew(('\n' as u32) as u16);
+        self.input
+            .extend(line.iter().map(|&b| Value::mew(b as u16)));
+        self.input.push_back(MEWLINE);
+        self.input_log
+            .push(String::from_utf8_lossy(&line).into_owned());
+    }
+
+    fn filter_non_ascii(&mut self, mut line: Vec<u8>) -> LineAction {
+        match self.non_ascii_policy {
+            NonAsciiPolicy::Reject => {
+                let line = String::from_utf8_lossy(&line);
+                if let Some((i, c)) = line.chars().enumerate().find(|(_, c)| !c.is_ascii()) {
+                    status!(
+                        self,
+                        "Cannot use non-ascii character {c:?} at position {}!",
+                        i + 1
+                    );
+                }
+                LineAction::Skip
+            }
+            NonAsciiPolicy::Strip => {
+                line.retain(u8::is_ascii);
+                LineAction::Feed(line)
+            }
+            // One space per character, not per byte
+            NonAsciiPolicy::ReplaceWithSpace => LineAction::Feed(
+                String::from_utf8_lossy(&line)
+                    .chars()
+                    .map(|c| if c.is_ascii() { c } else { ' ' })
+                    .collect::<String>()
+                    .into_bytes(),
+            ),
+        }
+    }
+
+    fn register_list(&self) -> String {
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("r{i}={value}"))
+            .collect();
+        registers.join(" ")
+    }
+
+    fn print_registers(&mut self) {
+        let registers = self.register_list();
+        status!(self, "{registers}");
+    }
+
+    fn disasm_command(&mut self, count: usize) {
+        for line in disassemble_from(&self.memory, self.pc, count) {
+            status!(self, "{line}");
+        }
+    }
+
+    fn step_command(&mut self, count: &str) {
+        match count.parse().ok().filter(|&count| count > 0) {
+            Some(count) => self.step_budget = Some(count),
+            None => status!(self, "{count} is not a number of instructions"),
+        }
+    }
+
+    /// Where `!step` stops once its instructions have run. The program isn't
+    /// waiting for input here, so only debugging commands (and `exit`) work.
+    /// A blank line steps once more and `!continue` goes back to running
+    /// normally. The program's output goes wherever it usually does, and is
+    /// flushed before stopping so it's all visible by the time this prompts.
+    fn debug_prompt(&mut self) -> Option<HaltReason> {
+        // A failed flush will turn up again as an error from the next `out`
+        let _ = self.output.flush();
+        for line in disassemble_from(&self.memory, self.pc, 1) {
+            status!(self, "=== Stopped at {line} ===");
+        }
+        while self.step_budget == Some(0) {
+            let Some(line) = self.read_line() else {
+                return Some(HaltReason::EndOfInput);
+            };
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if line.is_empty() {
+                self.step_budget = Some(1);
+            } else if !line.starts_with('!') && line != "exit" {
+                status!(
+                    self,
+                    "The program isn't waiting for input, try !step [n] or !continue"
+                );
+            } else if let LineAction::Exit = self.handle_line(line.to_owned()) {
+                return Some(HaltReason::Exit);
+            }
+        }
+        None
+    }
+
+    fn set_register_command(&mut self, register: &str, value: &str) {
+        let Some(value) = value
+            .parse::<u16>()
+            .ok()
+            .filter(|&n| n < self.arch.register_base())
+        else {
+            status!(self, "{value} is not a valid number");
+            return;
+        };
+        let Some(slot) = register.parse().ok().and_then(|r| self.reg_mut(r).ok()) else {
+            status!(self, "No such register {register}");
+            return;
+        };
+        // Written directly so it doesn't look like the program did it
+        *slot = Value::mew(value);
+        status!(self, "r{register}={value}");
+    }
+
+    /// A few lines on where the program ended up, for checking a run did what
+    /// it should. Each line is `name: value`, in this order:
+    ///
+    /// ```text
+    /// halt reason: Halt
+    /// instructions: 1234
+    /// registers: r0=0 r1=0 r2=0 r3=0 r4=0 r5=0 r6=0 r7=0
+    /// stack depth: 0
+    /// ```
+    ///
+    /// The halt reason is `Running` if the program hasn't halted, which
+    /// includes stopping with an error.
+    pub fn state_summary(&self) -> String {
+        let halt_reason = match self.halt_reason {
+            Some(reason) => format!("{reason:?}"),
+            None => "Running".to_owned(),
+        };
+        format!(
+            "halt reason: {halt_reason}\ninstructions: {}\nregisters: {}\nstack depth: {}\n",
+            self.instruction_count,
+            self.register_list(),
+            self.stack.len()
+        )
+    }
+
+    fn parse_address(&mut self, addr: &str) -> Option<usize> {
+        let parsed = addr.parse().ok().filter(|&addr| addr < self.memory.len());
+        if parsed.is_none() {
+            status!(self, "{addr} is not an address");
+        }
+        parsed
+    }
+
+    fn peek_command(&mut self, addr: &str) {
+        if let Some(addr) = self.parse_address(addr) {
+            status!(self, "{addr}: {}", self.memory[addr]);
+        }
+    }
+
+    /// Any word at all can go in memory, even one that's no use as a number
+    fn poke_command(&mut self, addr: &str, value: &str) {
+        let Some(addr) = self.parse_address(addr) else {
+            return;
+        };
+        let Ok(value) = value.parse::<u16>() else {
+            status!(self, "{value} is not a valid word");
+            return;
+        };
+        // Written directly so it doesn't look like the program did it
+        self.memory[addr] = Value::mew(value);
+        status!(self, "{addr}: {value}");
+    }
+
+    fn dump_state(&mut self) {
+        status!(
+            self,
+            "pc={} instructions={}",
+            self.pc,
+            self.instruction_count
+        );
+        self.print_registers();
+        let top: Vec<String> = self
+            .stack
+            .iter()
+            .rev()
+            .take(DUMP_STACK_DEPTH)
+            .map(Value::to_string)
+            .collect();
+        status!(
+            self,
+            "stack ({} deep, top first): {}",
+            self.stack.len(),
+            top.join(" ")
+        );
+    }
+
+    fn save(&mut self, name: &str) {
+        match self.write_save(name) {
+            Ok(()) => status!(self, "=== State Saved ==="),
+            Err(err) => status!(self, "Could not save: {err}"),
+        }
+    }
+
+    fn write_save(&mut self, name: &str) -> Result<(), SaveError> {
+        let mut data = Vec::new();
+        self.save_to(&mut data, SaveFormat::Ron)?;
+        Ok(self.storage.write(name, &data)?)
+    }
+
+    fn read_save(&mut self, name: &str) -> Result<VM, SaveError> {
+        let data = self.storage.read(name)?;
+        VM::load_from(data.as_slice(), SaveFormat::Ron)
+    }
+
+    fn list_saves(&mut self) {
+        let mut names: Vec<String> = match self.storage.list(SAVE_DIR) {
+            Ok(names) => names
+                .into_iter()
+                .filter_map(|name| name.strip_suffix(".ron").map(str::to_owned))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        names.sort();
+        if names.is_empty() {
+            status!(self, "No saves");
+        }
+        for name in names {
+            status!(self, "{name}");
+        }
+    }
+
+    fn load(&mut self, name: &str) -> LineAction {
+        // Only touch the current state once the whole save has been read
+        match self.read_save(name) {
+            Ok(loaded) => {
+                self.restore_state(loaded);
+                status!(self, "=== State Loaded ===");
+                LineAction::Feed(b"look".to_vec())
+            }
+            Err(err) => {
+                status!(self, "Could not load save: {err}");
+                LineAction::Skip
+            }
+        }
+    }
+
+    /// Stops at the debug prompt if `!step` has used up its instructions,
+    /// returning why the program should stop if the prompt says so
+    fn check_step_budget(&mut self) -> Option<HaltReason> {
+        if self.step_budget != Some(0) {
+            return None;
+        }
+        let reason = self.debug_prompt()?;
+        self.halt_reason = Some(reason);
+        Some(reason)
+    }
+
+    pub fn step(&mut self) -> Result<ExecutionState, VmError> {
+        if let Some(reason) = self.check_step_budget() {
+            return Ok(ExecutionState::Complete(reason));
+        }
+        let pc = self.pc;
+        let opcode = self.get_instruction()?;
+        self.validate_operands(opcode)?;
+        if let Some(trace) = &mut self.trace {
+            let operands = &self.memory[self.pc + 1..=self.pc + opcode.num_args()];
+            trace(self.pc, &opcode, operands);
+        }
+        if self.history.is_some() {
+            self.undo = Some(Undo {
+                pc: self.pc,
+                opcode: opcode.to_u16() as usize,
+                ..Default::default()
+            });
+        }
+        if let Some(tracker) = &mut self.write_tracker {
+            tracker
+                .executed
+                .extend(self.pc..=self.pc + opcode.num_args());
+        }
+        let depth = self.stack.len();
+        if self.records.is_some() {
+            self.record = Some(StepRecord {
+                pc: self.pc,
+                opcode_num: opcode.to_u16(),
+                ..Default::default()
+            });
+        }
+        let result = self.execute(opcode);
+        if !matches!(result, Ok(ExecutionState::Running)) {
+            // Whatever happens next, the host wants to see the output so far.
+            // A failed flush will turn up again as an error from the next `out`
+            let _ = self.output.flush();
+        }
+        let undo = self.undo.take();
+        let record = self.record.take();
+        let watch_hit = self.watch_hit.take();
+        let mut state = result?;
+        self.instruction_count += 1;
+        self.opcode_counts[opcode.to_u16() as usize] += 1;
+        if let (Some(history), Some(undo)) = (&mut self.history, undo) {
+            history.record(undo);
+        }
+        if let (Some(records), Some(mut record)) = (&mut self.records, record) {
+            record.stack_delta = self.stack.len() as i32 - depth as i32;
+            records.push(record);
+        }
+        if let (ExecutionState::Running, Some(hit)) = (state, watch_hit) {
+            state = hit;
+        }
+        if let ExecutionState::Complete(reason) = state {
+            self.halt_reason = Some(reason);
+        }
+        if let Some(budget) = &mut self.step_budget {
+            // An `in` that stays put only handled a command, it didn't run
+            if !(opcode == Opcode::In && self.pc == pc) {
+                *budget = budget.saturating_sub(1);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Like `step`, but also says which instruction it ran. There's nothing to
+    /// report if the debug prompt stopped the program before it got that far.
+    pub fn step_and_report(
+        &mut self,
+    ) -> Result<(ExecutionState, Option<ExecutedInstruction>), VmError> {
+        if let Some(reason) = self.check_step_budget() {
+            return Ok((ExecutionState::Complete(reason), None));
+        }
+        let pc = self.pc;
+        // Anything that doesn't decode is left for `step` to complain about
+        let instruction = self.decode_at(pc).ok().and_then(|(opcode, count)| {
+            let operands = (1..=count)
+                .map(|offset| self.read_memory(pc + offset))
+                .collect::<Result<Vec<_>, _>>()
+                .ok()?;
+            let resolved = operands
+                .iter()
+                .map(|&v| self.resolve(v))
+                .collect::<Result<_, _>>()
+                .ok()?;
+            Some(ExecutedInstruction {
+                pc,
+                opcode,
+                operands,
+                resolved,
+            })
+        });
+        let state = self.step()?;
+        Ok((state, instruction))
+    }
+
+    /// Starts remembering what the last `capacity` instructions changed so
+    /// they can be undone with `step_back`. This costs a little on every
+    /// step, so it's off by default.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// Calls `trace` before every instruction runs. Instructions that can't be
+    /// decoded never reach it, they fail with an error instead.
+    pub fn set_trace(&mut self, trace: TraceFn) {
+        self.trace = Some(trace);
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    /// Makes `push` and `call` fail with `VmError::StackOverflow` rather than
+    /// grow the stack past `depth` entries
+    pub fn set_max_stack_depth(&mut self, depth: usize) {
+        self.max_stack_depth = depth;
+    }
+
+    /// Starts (or stops) keeping track of which addresses get executed and
+    /// which get written to, for finding code that rewrites itself. Turning
+    /// it off throws away anything tracked so far.
+    pub fn track_writes(&mut self, enabled: bool) {
+        if enabled {
+            self.write_tracker.get_or_insert_with(WriteTracker::default);
+        } else {
+            self.write_tracker = None;
+        }
+    }
+
+    /// Starts (or stops) keeping a `StepRecord` of what every instruction
+    /// changes, to be collected with `take_records`. Turning it off throws
+    /// away anything not yet taken.
+    pub fn record_steps(&mut self, enabled: bool) {
+        if enabled {
+            self.records.get_or_insert_with(Vec::new);
+        } else {
+            self.records = None;
+        }
+    }
+
+    /// Everything recorded since the last call, oldest first. Instructions
+    /// that fail aren't recorded.
+    pub fn take_records(&mut self) -> Vec<StepRecord> {
+        self.records.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    /// A guess at the return addresses of the calls in progress, oldest
+    /// first, made by picking out the stack entries that come straight after
+    /// a `call`. The stack holds data as well, so this can include values
+    /// that just happen to look like return addresses, and misses frames the
+    /// program has rearranged. `shadow_call_stack` is more reliable.
+    pub fn call_stack(&self) -> Vec<usize> {
+        let call = Value::mew(Opcode::Call.to_u16());
+        self.stack
+            .iter()
+            .filter_map(|&value| self.arch.try_to_number(value).ok().map(usize::from))
+            .filter(|&addr| addr >= 2 && self.memory.get(addr - 2) == Some(&call))
+            .collect()
+    }
+
+    /// The return addresses of the calls in progress, oldest first, as
+    /// recorded by `call` and `ret` themselves. This isn't saved, so after
+    /// loading a save, restoring a snapshot, reloading the program or
+    /// stepping back over a `ret` it starts again from `call_stack`'s guess.
+    pub fn shadow_call_stack(&self) -> &[usize] {
+        &self.call_frames
+    }
+
+    /// Addresses that have both been executed and written to since
+    /// `track_writes` was turned on, in order
+    pub fn self_modified_addresses(&self) -> Vec<usize> {
+        let Some(tracker) = &self.write_tracker else {
+            return Vec::new();
+        };
+        let mut addresses: Vec<usize> = tracker
+            .executed
+            .intersection(&tracker.written)
+            .copied()
+            .collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    /// Reverses the most recently executed instruction
+    pub fn step_back(&mut self) -> Result<(), HistoryError> {
+        let history = self.history.as_mut().ok_or(HistoryError::Disabled)?;
+        let undo = history.pop().ok_or(HistoryError::Empty)?;
+        if let Some((value, read_line)) = undo.input {
+            if read_line {
+                // The queue was empty before this line was read
+                self.input.clear();
+                self.input_log.pop();
+            } else {
+                self.input.push_front(value);
+            }
+        }
+        if undo.pushed {
+            self.stack.pop();
+        }
+        if let Some(value) = undo.popped {
+            self.stack.push(value);
+        }
+        if let Some((address, value)) = undo.memory {
+            self.memory[address] = value;
+        }
+        if let Some((register, value)) = undo.register {
+            *self
+                .reg_mut(register)
+                .expect("Only registers set_register accepted are recorded") = value;
+        }
+        match Opcode::ALL[undo.opcode] {
+            Opcode::Call => {
+                self.call_frames.pop();
+            }
+            // There's no telling which frame it returned from
+            Opcode::Ret => self.call_frames = self.call_stack(),
+            _ => (),
+        }
+        self.pc = undo.pc;
+        // The counters may have been reset since this was recorded
+        self.instruction_count = self.instruction_count.saturating_sub(1);
+        self.opcode_counts[undo.opcode] = self.opcode_counts[undo.opcode].saturating_sub(1);
+        Ok(())
+    }
+
+    fn execute(&mut self, opcode: Opcode) -> Result<ExecutionState, VmError> {
+        match opcode {
+            Opcode::Halt => return Ok(ExecutionState::Complete(HaltReason::Halt)),
+            Opcode::Set => {
+                let target = self.get_memory(1)?;
+                let target = self
+                    .arch
+                    .try_to_register(target)
+                    .map_err(|error| self.bad_value(error))?;
+                let value = self.get_value(2)?;
+                self.set_register(target, value)?;
+            }
+            Opcode::Push => {
+                let value = self.get_value(1)?;
+                self.push_stack(value)?;
+            }
+            Opcode::Pop => {
+                let target = self.get_memory(1)?;
+                let value = self
+                    .pop_stack()
+                    .ok_or(VmError::StackUnderflow { pc: self.pc })?;
+                self.set_memory(target, value)?;
+            }
+            Opcode::Eq => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                let equal = self
+                    .arch
+                    .equals(a, b)
+                    .map_err(|error| self.bad_value(error))?;
+                let value = if equal { 1 } else { 0 };
+                self.set_memory(target, Value::mew(value))?;
+            }
+            Opcode::Gt => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                let ordering = self
+                    .arch
+                    .compare_numeric(a, b)
+                    .map_err(|error| self.bad_value(error))?;
+                let value = if ordering == Ordering::Greater { 1 } else { 0 };
+                self.set_memory(target, Value::mew(value))?;
+            }
+            Opcode::Jmp => {
+                self.pc = self.get_number(1)? as usize;
+                // Avoid updating the pc
+                return Ok(ExecutionState::Running);
+            }
+            Opcode::Jt => {
+                let value = self.get_number(1)?;
+                if value != 0 {
+                    self.pc = self.get_number(2)? as usize;
+                    return Ok(ExecutionState::Running);
+                }
+            }
+            Opcode::Jf => {
+                let value = self.get_number(1)?;
+                if value == 0 {
+                    self.pc = self.get_number(2)? as usize;
+                    return Ok(ExecutionState::Running);
+                }
+            }
+            Opcode::Add => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                self.set_memory(target, self.arch.add(a, b))?;
+            }
+            Opcode::Mult => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                self.set_memory(target, self.arch.mult(a, b))?;
+            }
+            Opcode::Mod => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                let value = a
+                    .checked_rem(b)
+                    .ok_or(VmError::DivideByZero { pc: self.pc })?;
+                self.set_memory(target, value)?;
+            }
+            Opcode::And => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                self.set_memory(target, a & b)?;
+            }
+            Opcode::Or => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                let b = self.get_value(3)?;
+                self.set_memory(target, a | b)?;
+            }
+            Opcode::Not => {
+                let target = self.get_memory(1)?;
+                let a = self.get_value(2)?;
+                self.set_memory(target, self.arch.not(a))?;
+            }
+            Opcode::Rmem => {
+                let target = self.get_memory(1)?;
+                let location = self.get_number(2)? as usize;
+                let value = self.read_mapped(location)?;
+                self.set_memory(target, value)?;
+            }
+            Opcode::Wmem => {
+                let location = self.get_number(1)? as usize;
+                let value = self.get_value(2)?;
+                self.write_memory(location, value)?;
+            }
+            Opcode::Call => {
+                let a = self.get_number(1)?;
+                self.push_stack(Value::mew((self.pc + 2) as u16))?;
+                self.call_frames.push(self.pc + 2);
+                self.pc = a as usize;
+                return Ok(ExecutionState::Running);
+            }
+            Opcode::Ret => {
+                if let Some(value) = self.pop_stack() {
+                    self.pc = self
+                        .arch
+                        .try_to_number(value)
+                        .map_err(|error| self.bad_value(error))?
+                        as usize;
+                    return_to_frame(&mut self.call_frames, self.pc);
+                    return Ok(ExecutionState::Running);
+                } else {
+                    return Ok(ExecutionState::Complete(HaltReason::EmptyStackReturn));
+                }
+            }
+            Opcode::Out => {
+                let value = self.get_value(1)?;
+                let bytes = self.encode_output(value);
+                self.output
+                    .write_all(&bytes)
+                    .map_err(|err| VmError::OutputFailed {
+                        pc: self.pc,
+                        error: err.to_string(),
+                    })?;
+                if let Some(record) = &mut self.record {
+                    record.output = Some(value.raw() as u8);
+                }
+            }
+            Opcode::In => {
+                let read_line = self.input.is_empty();
+                if read_line && self.headless && self.preloaded.is_empty() {
+                    return Err(VmError::InputExhausted { pc: self.pc });
+                }
+                if read_line {
+                    let Some(line) = self.read_line() else {
+                        return Ok(ExecutionState::Complete(HaltReason::EndOfInput));
+                    };
+                    // Meta-commands are text, so anything that isn't goes
+                    // straight to the charset check rather than ending the run
+                    let action = match String::from_utf8(line) {
+                        Ok(line) => self.handle_line(line),
+                        Err(err) => self.check_charset(err.into_bytes()),
+                    };
+                    let line = match action {
+                        LineAction::Feed(line) => line,
+                        LineAction::Skip => return Ok(ExecutionState::Running),
+                        LineAction::Exit => return Ok(ExecutionState::Complete(HaltReason::Exit)),
+                    };
+                    self.queue_line(line);
+                }
+                let target = self.get_memory(1)?;
+                let value = self.input.pop_front().unwrap();
+                if let Some(undo) = &mut self.undo {
+                    undo.input = Some((value, read_line));
+                }
+                self.set_memory(target, value)?;
+            }
+            Opcode::Noop => (),
+        }
+        self.pc += opcode.num_args() + 1;
+        Ok(ExecutionState::Running)
+    }
+
+    /// Runs until the program stops, returning why it did. Watchpoints are
+    /// only honoured by `run_until_break`.
+    pub fn run(&mut self) -> Result<HaltReason, VmError> {
+        loop {
+            if let ExecutionState::Complete(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// A breakpoint that only stops at `addr` when `predicate` says so, for
+    /// things like catching the fourth time round a loop. It's checked on
+    /// arriving at `addr`, before the instruction there runs.
+    pub fn add_conditional_breakpoint(&mut self, addr: usize, predicate: BreakCondition) {
+        self.conditional_breakpoints
+            .entry(addr)
+            .or_default()
+            .push(predicate);
+    }
+
+    /// Removes every breakpoint at `addr`, conditional or not
+    pub fn remove_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.remove(&addr);
+        self.conditional_breakpoints.remove(&addr);
+    }
+
+    fn breakpoint_hit(&self, addr: usize) -> bool {
+        self.breakpoints.contains(&addr)
+            || self
+                .conditional_breakpoints
+                .get(&addr)
+                .is_some_and(|predicates| predicates.iter().any(|predicate| predicate(self)))
+    }
+
+    /// Stops execution with `ExecutionState::Watchpoint` straight after any
+    /// instruction that writes to register `reg`
+    pub fn add_register_watch(&mut self, reg: usize) {
+        self.register_watches.insert(reg);
+    }
+
+    pub fn remove_register_watch(&mut self, reg: usize) {
+        self.register_watches.remove(&reg);
+    }
+
+    /// Stops execution with `ExecutionState::Watchpoint` straight after any
+    /// instruction that writes to `addr`
+    pub fn add_memory_watch(&mut self, addr: usize) {
+        self.memory_watches.insert(addr);
+    }
+
+    pub fn remove_memory_watch(&mut self, addr: usize) {
+        self.memory_watches.remove(&addr);
+    }
+
+    /// Hands reads of `addr` by `rmem` and writes to it by any instruction
+    /// over to `handler` instead of memory, replacing whatever was mapped
+    /// there before. The program still executes whatever is in memory there,
+    /// and `peek_memory` shows that too. Writes to a mapped address aren't
+    /// recorded for watchpoints, write tracking or `step_back`.
+    pub fn map_io(&mut self, addr: usize, handler: Box<dyn IoHandler>) {
+        self.io.insert(addr, handler);
+    }
+
+    /// Like `run`, but hands control back when execution reaches a breakpoint,
+    /// before the instruction there runs. The instruction at the current pc is
+    /// always executed, so calling this again continues past the breakpoint.
+    pub fn run_until_break(&mut self) -> Result<ExecutionState, VmError> {
+        loop {
+            match self.step()? {
+                ExecutionState::Running if self.breakpoint_hit(self.pc) => {
+                    let _ = self.output.flush();
+                    return Ok(ExecutionState::Breakpoint(self.pc));
+                }
+                ExecutionState::Running => (),
+                state => return Ok(state),
+            }
+        }
+    }
+
+    /// Like `run`, but gives up with `VmError::StepLimitExceeded` once
+    /// `max_steps` instructions have run without the program stopping
+    pub fn run_with_limit(&mut self, max_steps: u64) -> Result<ExecutionState, VmError> {
+        for _ in 0..max_steps {
+            if let ExecutionState::Complete(reason) = self.step()? {
+                return Ok(ExecutionState::Complete(reason));
+            }
+        }
+        Err(VmError::StepLimitExceeded {
+            pc: self.pc,
+            steps: max_steps as usize,
+        })
+    }
+
+    /// Runs like `run`, but executes the simple instructions in a tight loop
+    /// that only keeps the instruction counters up to date. Anything unusual
+    /// (input, output, halting or an error) is left to `step` as normal. If
+    /// history, tracing, watchpoints, write tracking, step recording or
+    /// mapped I/O are turned on this is just `run`.
+    pub fn run_fast(&mut self) -> Result<HaltReason, VmError> {
+        let instrumented = self.history.is_some()
+            || self.write_tracker.is_some()
+            || self.records.is_some()
+            || self.trace.is_some()
+            || self.step_budget.is_some()
+            || !self.register_watches.is_empty()
+            || !self.memory_watches.is_empty()
+            || !self.io.is_empty();
+        if instrumented || self.arch != Arch::SYNACOR {
+            return self.run();
+        }
+        loop {
+            let memory = mem::take(&mut self.memory).into_boxed_slice();
+            let mut memory = match <Box<[Value; MEMORY_SIZE]>>::try_from(memory) {
+                Ok(memory) => memory,
+                Err(memory) => {
+                    self.memory = memory.into_vec();
+                    return self.run();
+                }
+            };
+            self.run_simple(&mut memory);
+            self.memory = (memory as Box<[Value]>).into_vec();
+            if let ExecutionState::Complete(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// The hot loop behind `run_fast`. Returns with the pc on the first
+    /// instruction it can't handle without checking anything.
+    fn run_simple(&mut self, memory: &mut [Value; MEMORY_SIZE]) {
+        const BASE: u16 = Value::REGISTER_BASE;
+        const MASK: u16 = Arch::SYNACOR.math_mask();
+        // The registers are indexed directly rather than through `reg_mut`
+        // to keep the loop tight. Every index comes from `value` or
+        // `register`, which check it's below `REGISTER_COUNT` first.
+        let registers = &mut self.registers;
+        let value = |registers: &[Value; Arch::REGISTER_COUNT], word: Value| match word.raw() {
+            n if n < BASE => Some(n),
+            r if usize::from(r - BASE) < Arch::REGISTER_COUNT => {
+                Some(registers[usize::from(r - BASE)].raw())
+            }
+            _ => None,
+        };
+        let register = |word: Value| {
+            let r = usize::from(word.raw().checked_sub(BASE)?);
+            (r < Arch::REGISTER_COUNT).then_some(r)
+        };
+        // Bails out to `step` on anything it doesn't like the look of
+        macro_rules! get {
+            ($e:expr) => {
+                match $e {
+                    Some(v) => v,
+                    None => return,
+                }
+            };
+        }
+        loop {
+            let pc = self.pc;
+            // Make sure even the longest instruction's operands are in range
+            if pc + 4 > MEMORY_SIZE {
+                return;
+            }
+            let a = memory[pc + 1];
+            let b = memory[pc + 2];
+            let c = memory[pc + 3];
+            let opcode = memory[pc].raw();
+            let next = match opcode {
+                1 => {
+                    registers[get!(register(a))] = Value::mew(get!(value(registers, b)));
+                    pc + 3
+                }
+                2 if self.stack.len() >= self.max_stack_depth => return,
+                2 => {
+                    self.stack.push(Value::mew(get!(value(registers, a))));
+                    pc + 2
+                }
+                3 => {
+                    let r = get!(register(a));
+                    registers[r] = get!(self.stack.pop());
+                    pc + 2
+                }
+                op @ (4 | 5 | 9 | 10 | 11 | 12 | 13) => {
+                    let r = get!(register(a));
+                    let b = u32::from(get!(value(registers, b)));
+                    let c = u32::from(get!(value(registers, c)));
+                    let result = match op {
+                        4 => u32::from(b == c),
+                        5 => u32::from(b > c),
+                        9 => b + c,
+                        10 => b * c,
+                        11 if c == 0 => return,
+                        11 => b % c,
+                        12 => b & c,
+                        _ => b | c,
+                    };
+                    registers[r] = Value::mew(result as u16 & MASK);
+                    pc + 4
+                }
+                6 => usize::from(get!(value(registers, a))),
+                op @ (7 | 8) => {
+                    let condition = get!(value(registers, a)) != 0;
+                    let target = usize::from(get!(value(registers, b)));
+                    if condition == (op == 7) {
+                        target
+                    } else {
+                        pc + 3
+                    }
+                }
+                14 => {
+                    let r = get!(register(a));
+                    registers[r] = Value::mew(!get!(value(registers, b)) & MASK);
+                    pc + 3
+                }
+                15 => {
+                    let r = get!(register(a));
+                    registers[r] = memory[usize::from(get!(value(registers, b)))];
+                    pc + 3
+                }
+                16 => {
+                    let address = usize::from(get!(value(registers, a)));
+                    memory[address] = Value::mew(get!(value(registers, b)));
+                    pc + 3
+                }
+                17 if self.stack.len() >= self.max_stack_depth => return,
+                17 => {
+                    let target = usize::from(get!(value(registers, a)));
+                    self.stack.push(Value::mew((pc + 2) as u16));
+                    self.call_frames.push(pc + 2);
+                    target
+                }
+                18 => {
+                    let Some(&address) = self.stack.last() else {
+                        return;
+                    };
+                    if address.raw() >= BASE {
+                        return;
+                    }
+                    self.stack.pop();
+                    return_to_frame(&mut self.call_frames, usize::from(address.raw()));
+                    usize::from(address.raw())
+                }
+                21 => pc + 1,
+                _ => return,
+            };
+            self.instruction_count += 1;
+            self.opcode_counts[usize::from(opcode)] += 1;
+            self.pc = next;
+        }
+    }
+
+    /// Runs the program to completion with `input` already queued up,
+    /// returning everything it printed and why it stopped. Nothing is read
+    /// from the terminal, so running out of input is an error rather than a
+    /// prompt. The output is handed back in the `HeadlessError` if it fails.
+    pub fn run_headless(&mut self, input: &str) -> Result<(String, HaltReason), HeadlessError> {
+        self.input
+            .extend(input.bytes().map(|b| Value::mew(b as u16)));
+        let captured = SharedBuffer::default();
+        let previous = mem::replace(&mut self.output, Box::new(captured.clone()));
+        self.headless = true;
+        let result = self.run();
+        self.headless = false;
+        self.output = previous;
+        let output = captured.into_string();
+        match result {
+            Ok(reason) => Ok((output, reason)),
+            Err(error) => Err(HeadlessError { error, output }),
+        }
+    }
+
+    /// Runs one instruction, unless it's a `call`, in which case the whole
+    /// subroutine runs and this stops once the stack is back down to where it
+    /// was, normally on the instruction after the call. Breakpoints inside the
+    /// subroutine still stop it early.
+    pub fn step_over(&mut self) -> Result<ExecutionState, VmError> {
+        if self.get_instruction()? != Opcode::Call {
+            return self.step();
+        }
+        let depth = self.stack.len();
+        let mut state = self.step()?;
+        while let ExecutionState::Running = state {
+            if self.stack.len() <= depth {
+                break;
+            }
+            if self.breakpoint_hit(self.pc) {
+                return Ok(ExecutionState::Breakpoint(self.pc));
+            }
+            state = self.step()?;
+        }
+        Ok(state)
+    }
+
+    /// Runs until the program's output ends with `needle`, stopping straight
+    /// after the `out` that completes it with `ExecutionState::Running`. If
+    /// the program stops first, including by running out of input, that's
+    /// returned instead.
+    pub fn run_until_output(&mut self, needle: &str) -> Result<ExecutionState, VmError> {
+        let needle = needle.as_bytes();
+        let mut seen = Vec::new();
+        loop {
+            let printed = match self.get_instruction() {
+                Ok(Opcode::Out) => self.get_value(1).ok(),
+                _ => None,
+            };
+            let state = self.step()?;
+            if let Some(value) = printed {
+                seen.extend(self.encode_output(value));
+                if seen.ends_with(needle) {
+                    return Ok(state);
+                }
+                // Only the tail can ever be part of a match
+                seen.drain(..seen.len().saturating_sub(needle.len()));
+            }
+            if let ExecutionState::Complete(_) = state {
+                return Ok(state);
+            }
+        }
+    }
+
+    /// Runs until the stack is `delta` entries deeper (or shallower, if
+    /// negative) than it is now, giving up after `max_steps` instructions
+    pub fn run_until_stack_delta(
+        &mut self,
+        delta: i64,
+        max_steps: usize,
+    ) -> Result<ExecutionState, VmError> {
+        let start_depth = self.stack.len() as i64;
+        for _ in 0..max_steps {
+            if let ExecutionState::Complete(reason) = self.step()? {
+                return Ok(ExecutionState::Complete(reason));
+            }
+            if self.stack.len() as i64 - start_depth == delta {
+                return Ok(ExecutionState::Running);
+            }
+        }
+        Err(VmError::StepLimitExceeded {
+            pc: self.pc,
+            steps: max_steps,
+        })
     }
 }
@@ -1,25 +1,164 @@
-use std::fs::File;
-use std::io::Read;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter};
+use std::process;
 
-use vm_challenge::machine::VM;
+use vm_challenge::disasm::{disassemble_code, format_instruction};
+use vm_challenge::machine::{LoadError, MAX_PROGRAM_WORDS, VM};
+use vm_challenge::storage::FileStorage;
+
+const DEFAULT_PROGRAM: &str = "challenge.bin";
 
 fn main() {
-    let data = read_program();
-    let mut machine = VM::new(data);
+    let mut path = None;
+    let mut trace = false;
+    let mut summary = false;
+    let mut echo = false;
+    let mut input = None;
+    let mut args = env::args().skip(1).peekable();
+    if args.peek().is_some_and(|arg| arg == "disasm") {
+        let args: Vec<String> = args.skip(1).collect();
+        let [program, listing] = args.as_slice() else {
+            eprint!(
+                "disasm needs a program and somewhere to write the listing\n\n{}",
+                usage()
+            );
+            process::exit(2);
+        };
+        if let Err(err) = write_listing(program, listing) {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+        return;
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", usage());
+                return;
+            }
+            "--trace" => trace = true,
+            "--summary" => summary = true,
+            "--echo-input" => echo = true,
+            "--input" => match args.next() {
+                Some(file) => input = Some(file),
+                None => {
+                    eprint!("--input needs a file\n\n{}", usage());
+                    process::exit(2);
+                }
+            },
+            _ if arg.starts_with('-') || path.is_some() => {
+                eprint!("Unexpected argument {arg}\n\n{}", usage());
+                process::exit(2);
+            }
+            _ => path = Some(arg),
+        }
+    }
+    let mut machine = match load_program(path.as_deref().unwrap_or(DEFAULT_PROGRAM)) {
+        Ok(machine) => machine,
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    };
+    use_std_io(&mut machine);
+    if let Some(file) = input {
+        match fs::read_to_string(&file) {
+            Ok(script) => machine.preload_input(&script),
+            Err(err) => {
+                eprintln!("Could not read {file}: {err}");
+                process::exit(1);
+            }
+        }
+    }
+    machine.set_echo_input(echo);
+    if trace {
+        machine.set_trace(Box::new(|pc, opcode, args| {
+            eprintln!("{}", format_instruction(pc, opcode, args));
+        }));
+    }
     println!("=== Starting VM ===");
-    machine.run();
+    let result = machine.run();
     println!();
-    println!("=== Execution Complete ===");
+    match &result {
+        Ok(_) => println!("=== Execution Complete ==="),
+        Err(err) => {
+            eprintln!("{err}");
+            println!("=== Execution Failed ===");
+        }
+    }
+    if summary {
+        eprint!("{}", machine.state_summary());
+    }
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+fn usage() -> String {
+    format!(
+        "Usage: vm_challenge [OPTIONS] [PROGRAM]
+       vm_challenge disasm PROGRAM LISTING
+
+Runs PROGRAM (default {DEFAULT_PROGRAM}) on the Synacor VM, or with disasm
+writes its disassembly to LISTING instead. Only code reachable from the
+start is disassembled, anything else is listed as db.
+
+Options:
+  --echo-input  Repeat each line read from stdin after the prompt, so a
+                transcript of a session with redirected input makes sense
+  --input FILE  Type in each line of FILE before prompting for anything
+  --summary     Print why the program stopped, how many instructions it ran
+                and the final registers and stack depth to stderr at the end
+  --trace       Print every instruction to stderr before it runs, one per line
+                as `ADDR: mnemonic operands...` (see disasm::format_instruction)
+  -h, --help    Show this message
+"
+    )
+}
+
+/// Hooks the VM up to the terminal and working directory: a prompt on stdin,
+/// program output on stdout, messages on stderr and saves in files. The
+/// output is buffered, as locking stdout for every character is slow, and
+/// the VM flushes it whenever the program reads input and whenever it stops.
+fn use_std_io(machine: &mut VM) {
+    machine.set_input(io::stdin().lock());
+    machine.set_prompt(true);
+    machine.set_output(BufWriter::new(io::stdout()));
+    machine.set_status(io::stderr());
+    machine.set_storage(FileStorage);
+}
+
+/// Reads a program image, turning away anything too big to load by its size
+/// alone rather than reading it all in first
+fn read_program(path: &str) -> Result<Vec<u8>, String> {
+    let len = fs::metadata(path)
+        .map_err(|err| format!("Could not read {path}: {err}"))?
+        .len();
+    if len > (MAX_PROGRAM_WORDS * 2) as u64 {
+        let words = (len / 2) as usize;
+        return Err(format!(
+            "Could not load {path}: {}",
+            LoadError::TooLarge {
+                words,
+                max: MAX_PROGRAM_WORDS
+            }
+        ));
+    }
+    fs::read(path).map_err(|err| format!("Could not read {path}: {err}"))
+}
+
+fn load_program(path: &str) -> Result<VM, String> {
+    let data = read_program(path)?;
+    VM::from_bytes(&data).map_err(|err| format!("Could not load {path}: {err}"))
 }
 
-fn read_program() -> Vec<u16> {
-    let mut file =
-        File::open("challenge.bin").expect("Challenge bin file should be in the working directory");
-    let mut raw_data = Vec::with_capacity(file.metadata().unwrap().len() as usize);
-    file.read_to_end(&mut raw_data).unwrap();
-    assert_eq!(raw_data.len() % 2, 0);
-    raw_data
-        .chunks_exact(2)
-        .map(|chunk| u16::from_le_bytes(chunk.try_into().unwrap()))
-        .collect()
+fn write_listing(program: &str, listing: &str) -> Result<(), String> {
+    let data = read_program(program)?;
+    let machine =
+        VM::from_bytes(&data).map_err(|err| format!("Could not load {program}: {err}"))?;
+    // Leave off the zeroes the VM pads memory out with
+    let lines = disassemble_code(&machine.memory()[..data.len() / 2]);
+    let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+    fs::write(listing, text).map_err(|err| format!("Could not write {listing}: {err}"))
 }
@@ -0,0 +1,31 @@
+//! Helpers for the puzzles in the challenge that are quicker to solve with a
+//! computer than by hand
+
+/// What the equation carved into the wall in the ruins has to come out as
+const COIN_TARGET: i64 = 399;
+
+/// Finds the order to put the coins in so that `a + b * c^2 + d^3 - e`
+/// comes out as 399, trying every permutation
+pub fn solve_coins(coins: &[u16; 5]) -> Option<[u16; 5]> {
+    let mut order = [0; 5];
+    try_orders(coins, &mut order, 0, 0)
+}
+
+/// Fills in `order` from `depth` onwards with every coin that isn't in
+/// `used` yet, checking each complete order against the equation
+fn try_orders(coins: &[u16; 5], order: &mut [u16; 5], depth: usize, used: u8) -> Option<[u16; 5]> {
+    if depth == coins.len() {
+        let [a, b, c, d, e] = order.map(i64::from);
+        return (a + b * c.pow(2) + d.pow(3) - e == COIN_TARGET).then_some(*order);
+    }
+    for (i, &coin) in coins.iter().enumerate() {
+        if used & (1 << i) != 0 {
+            continue;
+        }
+        order[depth] = coin;
+        if let Some(solution) = try_orders(coins, order, depth + 1, used | (1 << i)) {
+            return Some(solution);
+        }
+    }
+    None
+}
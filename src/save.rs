@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+
+use crate::machine::VM;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    #[default]
+    Ron,
+    /// Easier to diff and hand-edit with standard tooling
+    Json,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Ron(ron::Error),
+    RonParse(ron::error::SpannedError),
+    Json(serde_json::Error),
+}
+
+impl Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Ron(err) => err.fmt(f),
+            Self::RonParse(err) => err.fmt(f),
+            Self::Json(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for SaveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Ron(err) => Some(err),
+            Self::RonParse(err) => Some(err),
+            Self::Json(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<ron::Error> for SaveError {
+    fn from(err: ron::Error) -> Self {
+        Self::Ron(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for SaveError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        Self::RonParse(err)
+    }
+}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl VM {
+    pub fn save_to<W: Write>(&self, w: W, format: SaveFormat) -> Result<(), SaveError> {
+        match format {
+            SaveFormat::Ron => ron::ser::to_writer_pretty(w, self, Default::default())?,
+            SaveFormat::Json => serde_json::to_writer_pretty(w, self)?,
+        }
+        Ok(())
+    }
+
+    /// Reads a saved VM. Anything that isn't part of a save, such as where
+    /// input and output go, is left at its defaults. The program isn't saved
+    /// separately, so `reset` goes back to the memory as it was saved.
+    pub fn load_from<R: Read>(r: R, format: SaveFormat) -> Result<VM, SaveError> {
+        let mut vm: VM = match format {
+            SaveFormat::Ron => ron::de::from_reader(r)?,
+            SaveFormat::Json => serde_json::from_reader(r)?,
+        };
+        // Saves from before memory was padded only have the program's words
+        vm.fill_address_space();
+        vm.set_initial_memory();
+        Ok(vm)
+    }
+
+    /// Writes out the memory in the same little-endian format programs are
+    /// loaded from, patches and all. This is the whole address space, so a
+    /// program shorter than that comes back padded with zeroes.
+    pub fn dump_memory_image<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let bytes: Vec<u8> = self
+            .memory()
+            .iter()
+            .flat_map(|value| value.raw().to_le_bytes())
+            .collect();
+        w.write_all(&bytes)
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where the `save`, `load`, `list saves` and `dumpscript` commands keep
+/// their files. Names are `/` separated paths, such as `saves/start.ron`.
+pub trait Storage {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>>;
+    /// Replaces anything already stored under `name`
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()>;
+    /// The names of everything directly inside `dir`, without the `dir/`
+    fn list(&mut self, dir: &str) -> io::Result<Vec<String>>;
+}
+
+/// Files relative to the working directory, which is what the CLI uses
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileStorage;
+
+impl Storage for FileStorage {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(name)
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let path = Path::new(name);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, data)
+    }
+
+    fn list(&mut self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .collect())
+    }
+}
+
+/// Keeps everything in memory, for when there's no filesystem to hand
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        self.files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No file {name}")))
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        self.files.insert(name.to_owned(), data.to_vec());
+        Ok(())
+    }
+
+    fn list(&mut self, dir: &str) -> io::Result<Vec<String>> {
+        let prefix = format!("{dir}/");
+        Ok(self
+            .files
+            .keys()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .filter(|name| !name.contains('/'))
+            .map(str::to_owned)
+            .collect())
+    }
+}
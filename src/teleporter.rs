@@ -0,0 +1,65 @@
+//! The teleporter's confirmation routine (at 6027 in the challenge binary) is
+//! a close cousin of the Ackermann function, using r7 as an extra parameter:
+//!
+//! ```text
+//! f(0, n) = n + 1
+//! f(m, 0) = f(m - 1, r7)
+//! f(m, n) = f(m - 1, f(m, n - 1))
+//! ```
+//!
+//! with everything mod 32768. The game calls it with `f(4, 1)` and wants 6
+//! back. Running that on the VM takes forever, so this works it out directly.
+
+use crate::value::Arch;
+
+const MODULUS: u32 = Arch::SYNACOR.math_mod();
+
+/// The levels of `f` up to 2 can be written out in full
+fn closed_form(m: u16, n: u32, r7: u32) -> u32 {
+    match m {
+        0 => (n + 1) % MODULUS,
+        1 => (n + r7 + 1) % MODULUS,
+        _ => (2 * r7 + 1 + n * (r7 + 1)) % MODULUS,
+    }
+}
+
+/// Evaluates `f(m, n)` for the given r7 by building up a table of each level
+/// below `m`, so it takes `O(m * 32768)` steps rather than forever
+pub fn confirmation(m: u16, n: u16, r7: u16) -> u16 {
+    let (n, r7) = (u32::from(n), u32::from(r7));
+    if m <= 2 {
+        return closed_form(m, n, r7) as u16;
+    }
+    // Level 2 is cheap enough to work out as it's needed, so the table starts
+    // out empty and only levels above that are stored
+    let lookup = |table: &[u32], i: u32| match table {
+        [] => closed_form(2, i, r7),
+        _ => table[i as usize],
+    };
+    let mut below = Vec::new();
+    for _ in 3..m {
+        let mut level = Vec::with_capacity(MODULUS as usize);
+        let mut value = lookup(&below, r7);
+        level.push(value);
+        for _ in 1..MODULUS {
+            value = lookup(&below, value);
+            level.push(value);
+        }
+        below = level;
+    }
+    let mut value = lookup(&below, r7);
+    for _ in 0..n {
+        value = lookup(&below, value);
+    }
+    value as u16
+}
+
+/// The first non-zero r7 for which `f(m, n)` comes out as `expected`
+pub fn solve_teleporter_with(m: u16, n: u16, expected: u16) -> Option<u16> {
+    (1..MODULUS as u16).find(|&r7| confirmation(m, n, r7) == expected)
+}
+
+/// The r7 the challenge binary's teleporter wants
+pub fn solve_teleporter() -> u16 {
+    solve_teleporter_with(4, 1, 6).expect("The standard teleporter should have a solution")
+}
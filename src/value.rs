@@ -1,57 +1,223 @@
+use std::cmp::Ordering;
+use std::error::Error;
 use std::fmt::Display;
 use std::ops;
 
 use serde::{Deserialize, Serialize};
 
-const MATH_MOD: u32 = 32_768;
-const MATH_MASK: u16 = !(MATH_MOD as u16);
+/// A value couldn't be used the way an instruction wanted. Each variant says
+/// what was expected and carries the raw word that was found instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueError {
+    ExpectedRegister(u16),
+    ExpectedNumber(u16),
+    ExpectedChar(u16),
+    /// Neither a number nor a register on the machine it was used on
+    Invalid(u16),
+}
+
+impl Display for ValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::ExpectedRegister(raw) => {
+                write!(f, "Attempted to use number {raw} as a register")
+            }
+            // Which register depends on the arch, which isn't known here
+            Self::ExpectedNumber(raw) => {
+                write!(f, "Attempted to use a register ({raw}) as a number")
+            }
+            Self::ExpectedChar(raw) => write!(f, "Value {raw} is not a valid character"),
+            Self::Invalid(raw) => write!(f, "Attempted to use invalid number {raw}"),
+        }
+    }
+}
+
+impl Error for ValueError {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueState {
     Number(u16),
     Register(usize),
     Invalid,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+/// The shape of the machine's words. Numbers are `0..2^bits`, the registers
+/// are encoded directly above them and all math wraps at `2^bits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arch {
+    bits: u32,
+}
+
+impl Arch {
+    pub const REGISTER_COUNT: usize = 8;
+
+    pub const SYNACOR: Self = Self::new(15);
+
+    pub const fn new(bits: u32) -> Self {
+        // Any more and the register encodings stop fitting in a u16
+        assert!(bits >= 1 && bits <= 15, "Word size must be 1 to 15 bits");
+        Self { bits }
+    }
+
+    pub const fn math_mod(self) -> u32 {
+        1 << self.bits
+    }
+
+    /// How many words of memory there are, which is also the most a
+    /// program can be
+    pub const fn address_space(self) -> usize {
+        self.math_mod() as usize
+    }
+
+    pub const fn math_mask(self) -> u16 {
+        (self.math_mod() - 1) as u16
+    }
+
+    pub const fn register_base(self) -> u16 {
+        self.math_mod() as u16
+    }
+
+    pub fn value_state(self, value: Value) -> ValueState {
+        let register_base = self.register_base();
+        match value.0 {
+            value if value < register_base => ValueState::Number(value),
+            value if value < register_base + Self::REGISTER_COUNT as u16 => {
+                ValueState::Register((value - register_base) as usize)
+            }
+            _ => ValueState::Invalid,
+        }
+    }
+
+    pub fn try_to_register(self, value: Value) -> Result<usize, ValueError> {
+        match self.value_state(value) {
+            ValueState::Register(i) => Ok(i),
+            ValueState::Number(_) => Err(ValueError::ExpectedRegister(value.0)),
+            ValueState::Invalid => Err(ValueError::Invalid(value.0)),
+        }
+    }
+
+    pub fn try_to_number(self, value: Value) -> Result<u16, ValueError> {
+        match self.value_state(value) {
+            ValueState::Number(num) => Ok(num),
+            ValueState::Register(_) => Err(ValueError::ExpectedNumber(value.0)),
+            ValueState::Invalid => Err(ValueError::Invalid(value.0)),
+        }
+    }
+
+    /// Orders two numbers by what they're worth, refusing anything that's
+    /// still register-encoded or invalid rather than comparing raw words
+    pub fn compare_numeric(self, a: Value, b: Value) -> Result<Ordering, ValueError> {
+        Ok(self.try_to_number(a)?.cmp(&self.try_to_number(b)?))
+    }
+
+    /// Equality is only defined on numbers, so this fails if either side is
+    /// still register-encoded or invalid rather than comparing raw words
+    pub fn equals(self, a: Value, b: Value) -> Result<bool, ValueError> {
+        Ok(self.compare_numeric(a, b)? == Ordering::Equal)
+    }
+
+    pub fn add(self, a: Value, b: Value) -> Value {
+        self.mew_from_math(a.math_value() + b.math_value())
+    }
+
+    /// Subtraction isn't an instruction, but wraps the same way `add` does,
+    /// so `2 - 5` is `2^bits - 3`
+    pub fn sub(self, a: Value, b: Value) -> Value {
+        let b = b.math_value() % self.math_mod();
+        self.mew_from_math(a.math_value() + self.math_mod() - b)
+    }
+
+    pub fn mult(self, a: Value, b: Value) -> Value {
+        self.mew_from_math(a.math_value() * b.math_value())
+    }
+
+    pub fn not(self, a: Value) -> Value {
+        Value(!a.0 & self.math_mask())
+    }
+
+    fn mew_from_math(self, value: u32) -> Value {
+        Value((value % self.math_mod()) as u16)
+    }
+}
+
+impl Default for Arch {
+    fn default() -> Self {
+        Self::SYNACOR
+    }
+}
+
+/// A word of memory. The methods that need to know how words are encoded,
+/// and the arithmetic operators, go by `Arch::SYNACOR`; the same things on
+/// `Arch` work for any machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Value(u16);
 
 impl Value {
+    /// The first register encoding, 32768
+    pub const REGISTER_BASE: u16 = Arch::SYNACOR.register_base();
+    /// The largest number a value can hold, 32767
+    pub const MAX_NUMBER: u16 = Self::REGISTER_BASE - 1;
+    pub const REGISTER_COUNT: usize = Arch::REGISTER_COUNT;
+
     pub const fn mew(value: u16) -> Self {
         Self(value)
     }
 
+    pub const fn raw(self) -> u16 {
+        self.0
+    }
+
     pub fn get_value_state(self) -> ValueState {
-        match self.0 {
-            value if value <= 32767 => ValueState::Number(value),
-            value if value <= 32775 => ValueState::Register((value - 32768) as usize),
-            _ => ValueState::Invalid,
-        }
+        Arch::SYNACOR.value_state(self)
+    }
+
+    pub fn try_to_register(self) -> Result<usize, ValueError> {
+        Arch::SYNACOR.try_to_register(self)
+    }
+
+    pub fn try_to_number(self) -> Result<u16, ValueError> {
+        Arch::SYNACOR.try_to_number(self)
+    }
+
+    /// Despite the name this accepts any character, only the surrogate
+    /// range is rejected. Use `to_char` to never fail.
+    pub fn try_to_ascii(self) -> Result<char, ValueError> {
+        char::from_u32(self.0.into()).ok_or(ValueError::ExpectedChar(self.0))
     }
 
     pub fn to_register(self) -> usize {
-        match self.get_value_state() {
-            ValueState::Number(num) if num < 8 => panic!("TODO: Reckon this shouldn't be valid"),
-            ValueState::Number(_) => panic!("Attempted to use a number as a register"),
-            ValueState::Register(i) => i,
-            ValueState::Invalid => panic!("Attempted to use invalid number {}", self.0),
-        }
+        self.try_to_register().unwrap_or_else(|err| panic!("{err}"))
     }
 
     pub fn to_number(self) -> u16 {
-        match self.get_value_state() {
-            ValueState::Number(num) => num,
-            ValueState::Register(i) => panic!("Attempted to use register {i} as a number!"),
-            ValueState::Invalid => panic!("Attempted to use invalid number {}", self.0),
-        }
+        self.try_to_number().unwrap_or_else(|err| panic!("{err}"))
     }
 
     pub fn to_ascii(self) -> char {
-        char::from_u32(self.0.into()).expect("Value must be a valid ascii character")
+        self.try_to_ascii().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// The character with this code point, or U+FFFD for the surrogates,
+    /// which aren't characters on their own
+    pub fn to_char(self) -> char {
+        char::from_u32(self.0.into()).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+
+    pub fn compare_numeric(self, other: Value) -> Result<Ordering, ValueError> {
+        Arch::SYNACOR.compare_numeric(self, other)
     }
 
-    fn mew_from_math(value: u32) -> Self {
-        Self((value % MATH_MOD) as u16)
+    pub fn equals(self, other: Value) -> Result<bool, ValueError> {
+        Arch::SYNACOR.equals(self, other)
+    }
+
+    pub fn checked_rem(self, rhs: Self) -> Option<Self> {
+        self.0.checked_rem(rhs.0).map(Self)
+    }
+
+    /// Integer division, or `None` when dividing by zero
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        self.0.checked_div(rhs.0).map(Self)
     }
 
     fn math_value(self) -> u32 {
@@ -63,17 +229,28 @@ impl ops::Add for Value {
     type Output = Self;
 
     fn add(self, rhs: Self) -> Self::Output {
-        Self::mew_from_math(self.math_value() + rhs.math_value())
+        Arch::SYNACOR.add(self, rhs)
+    }
+}
+
+/// Wraps around mod 32768 like the challenge's own arithmetic
+impl ops::Sub for Value {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Arch::SYNACOR.sub(self, rhs)
     }
 }
 
 impl ops::Mul for Value {
     type Output = Self;
     fn mul(self, rhs: Self) -> Self::Output {
-        Self::mew_from_math(self.math_value() * rhs.math_value())
+        Arch::SYNACOR.mult(self, rhs)
     }
 }
 
+/// Panics when `rhs` is zero, the same as dividing the integer types. Use
+/// `checked_rem` to get `None` instead, as the `mod` opcode does.
 impl ops::Rem for Value {
     type Output = Self;
     fn rem(self, rhs: Self) -> Self::Output {
@@ -100,7 +277,7 @@ impl ops::BitOr for Value {
 impl ops::Not for Value {
     type Output = Self;
     fn not(self) -> Self::Output {
-        Self(self.0.not() & MATH_MASK)
+        Arch::SYNACOR.not(self)
     }
 }
 